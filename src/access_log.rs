@@ -0,0 +1,145 @@
+// Copyright 2019-2021 Tauri Programme within The Commons Conservancy
+// SPDX-License-Identifier: Apache-2.0
+// SPDX-License-Identifier: MIT
+
+//! The access log, a line for each completed request written to `Invoke::access_log`.
+//!
+//! The lines are built from the `RequestRecord` of the requests, timestamped in UTC.
+
+use std::{
+  io::Write,
+  sync::{Arc, Mutex},
+  time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use serde_json::json;
+
+use crate::trace::{RequestLogger, RequestRecord};
+
+const MONTHS: [&str; 12] = [
+  "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+];
+
+/// The format of the access log lines.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AccessLogFormat {
+  /// The [Common Log Format](https://en.wikipedia.org/wiki/Common_Log_Format) of web servers,
+  /// such as `127.0.0.1 - - [10/Oct/2000:13:55:36 +0000] "GET /main/ping HTTP/1.1" 200 4`.
+  Common,
+  /// A JSON object per line, with the fields of the `RequestRecord` and the `timestamp`
+  /// of the request in RFC 3339.
+  JsonLines,
+}
+
+impl Default for AccessLogFormat {
+  fn default() -> Self {
+    Self::Common
+  }
+}
+
+/// The logger writing the access log lines to `writer`.
+pub(crate) fn logger<W: Write + Send + 'static>(
+  format: AccessLogFormat,
+  writer: W,
+) -> RequestLogger {
+  let writer = Mutex::new(writer);
+  Arc::new(move |record: &RequestRecord| {
+    let mut line = match format {
+      AccessLogFormat::Common => common_line(record),
+      AccessLogFormat::JsonLines => json_line(record),
+    };
+    line.push('\n');
+    let mut writer = writer.lock().unwrap();
+    // a failing log must not fail the requests
+    let _ = writer.write_all(line.as_bytes());
+    let _ = writer.flush();
+  })
+}
+
+fn common_line(record: &RequestRecord) -> String {
+  let (year, month, day, hours, minutes, seconds, _) = utc(started(record));
+  format!(
+    "{} - - [{:02}/{}/{}:{:02}:{:02}:{:02} +0000] \"{} {} HTTP/{}\" {} {}",
+    record
+      .client
+      .map_or_else(|| "-".to_string(), |client| client.ip().to_string()),
+    day,
+    MONTHS[month as usize - 1],
+    year,
+    hours,
+    minutes,
+    seconds,
+    record.method,
+    record.path,
+    record.http_version,
+    record
+      .status
+      .map_or_else(|| "-".to_string(), |status| status.to_string()),
+    record
+      .bytes
+      .map_or_else(|| "-".to_string(), |bytes| bytes.to_string()),
+  )
+}
+
+fn json_line(record: &RequestRecord) -> String {
+  json!({
+    "timestamp": rfc3339(started(record)),
+    "requestId": record.request_id,
+    "client": record.client.map(|client| client.to_string()),
+    "method": record.method,
+    "path": record.path,
+    "httpVersion": record.http_version,
+    "status": record.status,
+    "bytes": record.bytes,
+    "requestBytes": record.request_bytes,
+    "durationMs": record.duration.as_secs_f64() * 1000.0,
+    "window": record.window,
+    "cmd": record.cmd,
+    "origin": record.origin,
+    "traceId": record.trace_id,
+    "spanId": record.span_id,
+  })
+  .to_string()
+}
+
+/// A Unix time in RFC 3339, such as `2000-10-10T13:55:36.123Z`.
+pub(crate) fn rfc3339(time: Duration) -> String {
+  let (year, month, day, hours, minutes, seconds, millis) = utc(time);
+  format!(
+    "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z",
+    year, month, day, hours, minutes, seconds, millis
+  )
+}
+
+/// When the request was accepted.
+pub(crate) fn started(record: &RequestRecord) -> Duration {
+  let now = SystemTime::now()
+    .duration_since(UNIX_EPOCH)
+    .unwrap_or_default();
+  now.saturating_sub(record.duration)
+}
+
+/// The UTC year, month, day, hours, minutes, seconds and milliseconds of a Unix time.
+fn utc(time: Duration) -> (i64, u32, u32, u64, u64, u64, u32) {
+  let secs = time.as_secs();
+  let (days, secs) = ((secs / 86400) as i64, secs % 86400);
+  // http://howardhinnant.github.io/date_algorithms.html#civil_from_days
+  let z = days + 719468;
+  let era = z.div_euclid(146097);
+  let doe = z.rem_euclid(146097);
+  let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+  let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+  let mp = (5 * doy + 2) / 153;
+  let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+  let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+  let year = yoe + era * 400 + i64::from(month <= 2);
+  (
+    year,
+    month,
+    day,
+    secs / 3600,
+    secs % 3600 / 60,
+    secs % 60,
+    time.subsec_millis(),
+  )
+}
@@ -0,0 +1,452 @@
+// Copyright 2019-2021 Tauri Programme within The Commons Conservancy
+// SPDX-License-Identifier: Apache-2.0
+// SPDX-License-Identifier: MIT
+
+//! Batched invokes sent to `POST /__batch/{window}`.
+//!
+//! The body is an array of `{ cmd, args, callback }` items, each dispatched as its own invoke.
+//! The response is an array with the result of every item, in the same order,
+//! or a stream of newline delimited results sent as they complete with `Accept: application/x-ndjson`.
+
+use std::{
+  collections::HashMap,
+  sync::{
+    atomic::{AtomicUsize, Ordering},
+    Arc, Mutex,
+  },
+  time::{Duration, Instant},
+};
+
+use serde::Deserialize;
+use serde_json::{json, Map, Value as JsonValue};
+use tauri::{api::ipc::CallbackFn, AppHandle, InvokePayload, Runtime, Window};
+use tiny_http::{Header, Method, Request, Response};
+
+use crate::{
+  body_error, body_reader, check_extra_segments, cors, decode_segment, dispatch::Dispatcher, error,
+  error_status, header, invoke_window, merge_header_args, query_args, request_url, respond_value,
+  stream::ChunkedWriter, Config, ErrorCode, HttpError,
+};
+
+/// The media type of streamed batch results.
+const NDJSON: &str = "application/x-ndjson";
+
+/// A batch item waiting for the response of its command.
+pub(crate) struct PendingItem {
+  pub(crate) batch: Arc<Batch>,
+  pub(crate) index: usize,
+  pub(crate) window: String,
+  pub(crate) cmd: String,
+  pub(crate) started: Instant,
+}
+
+/// The batch items waiting for the command response, by callback id.
+pub(crate) type BatchItems = Mutex<HashMap<usize, PendingItem>>;
+
+#[derive(Deserialize)]
+struct BatchItem {
+  cmd: String,
+  #[serde(default)]
+  args: Map<String, JsonValue>,
+  /// Echoed in the item result, to correlate it on the client.
+  #[serde(default)]
+  callback: Option<JsonValue>,
+  #[serde(rename = "__TAURI_INVOKE_KEY__", default)]
+  invoke_key: Option<String>,
+}
+
+/// How the item results are rendered.
+pub(crate) enum Protocol {
+  /// `{ status, result | error, callback }` objects.
+  Batch,
+  /// JSON-RPC 2.0 response objects, a single one if the request wasn't a batch.
+  JsonRpc { single: bool },
+  /// An object with the `{ status, result | error }` of each window, the ids being the labels.
+  Broadcast,
+}
+
+enum Output {
+  /// The results are sent together once every item has responded.
+  Buffered {
+    request: Option<Request>,
+    results: Vec<Option<JsonValue>>,
+  },
+  /// Each result is sent as its own line, with the `index` of the item.
+  Stream(Option<ChunkedWriter>),
+}
+
+struct BatchState {
+  output: Output,
+  remaining: usize,
+}
+
+/// A batch request waiting for the responses of its items.
+pub(crate) struct Batch {
+  state: Mutex<BatchState>,
+  protocol: Protocol,
+  /// The batch `callback` or JSON-RPC `id` of each item, unset for JSON-RPC notifications.
+  ids: Vec<Option<JsonValue>>,
+}
+
+impl Batch {
+  /// Creates a batch answering `request` once every item has responded.
+  pub(crate) fn new(request: Request, protocol: Protocol, ids: Vec<Option<JsonValue>>) -> Self {
+    let remaining = ids.len();
+    Self {
+      state: Mutex::new(BatchState {
+        output: Output::Buffered {
+          request: Some(request),
+          results: vec![None; remaining],
+        },
+        remaining,
+      }),
+      protocol,
+      ids,
+    }
+  }
+
+  /// Creates a batch streaming its results to the client as they complete.
+  fn streamed(request: Request, ids: Vec<Option<JsonValue>>, config: &Config) -> Self {
+    let mut r = Response::empty(200u16);
+    cors(&request, &mut r, &config.allowed_origins);
+    let mut headers = r.headers().to_vec();
+    headers.push(Header::from_bytes("Content-Type", NDJSON).unwrap());
+    Self {
+      state: Mutex::new(BatchState {
+        output: Output::Stream(ChunkedWriter::start(request, 200, &headers).ok()),
+        remaining: ids.len(),
+      }),
+      protocol: Protocol::Batch,
+      ids,
+    }
+  }
+
+  /// Records the response of an item, answering the request once every item has responded.
+  pub(crate) fn complete(
+    &self,
+    index: usize,
+    response: Result<JsonValue, JsonValue>,
+    config: &Config,
+  ) {
+    let id = self.ids[index].clone();
+    let result = match self.protocol {
+      Protocol::Batch | Protocol::Broadcast => {
+        let mut result = match response {
+          Ok(value) => json!({ "status": 200, "result": value }),
+          Err(error) => json!({
+            "status": error_status(&error, config),
+            "code": ErrorCode::CommandError.as_str(),
+            "error": error,
+          }),
+        };
+        if let (Protocol::Batch, Some(callback)) = (&self.protocol, id) {
+          result["callback"] = callback;
+        }
+        Some(result)
+      }
+      // notifications are not answered
+      Protocol::JsonRpc { .. } => id.map(|id| match response {
+        Ok(value) => json!({ "jsonrpc": "2.0", "result": value, "id": id }),
+        Err(error) => {
+          let message = error.as_str().unwrap_or("command error").to_string();
+          json!({
+            "jsonrpc": "2.0",
+            "error": { "code": -32000, "message": message, "data": error },
+            "id": id,
+          })
+        }
+      }),
+    };
+    self.record(index, result, config);
+  }
+
+  /// Records the JSON-RPC error of an item that couldn't be dispatched.
+  pub(crate) fn fail(&self, index: usize, code: i64, message: &str, config: &Config) {
+    let result = self.ids[index].clone().map(|id| {
+      json!({
+        "jsonrpc": "2.0",
+        "error": { "code": code, "message": message },
+        "id": id,
+      })
+    });
+    self.record(index, result, config);
+  }
+
+  /// Records the error of an item that will never respond, as it wasn't dispatched or timed out.
+  pub(crate) fn fail_item(&self, index: usize, error: HttpError, config: &Config) {
+    let id = self.ids[index].clone();
+    let result = match self.protocol {
+      Protocol::Batch | Protocol::Broadcast => {
+        let mut result = json!({
+          "status": error.status,
+          "code": error.code.as_str(),
+          "error": error.message,
+        });
+        if let (Protocol::Batch, Some(callback)) = (&self.protocol, id) {
+          result["callback"] = callback;
+        }
+        Some(result)
+      }
+      Protocol::JsonRpc { .. } => id.map(|id| {
+        json!({
+          "jsonrpc": "2.0",
+          "error": {
+            "code": -32000,
+            "message": error.message,
+            "data": { "code": error.code.as_str() },
+          },
+          "id": id,
+        })
+      }),
+    };
+    self.record(index, result, config);
+  }
+
+  fn record(&self, index: usize, result: Option<JsonValue>, config: &Config) {
+    let mut state = self.state.lock().unwrap();
+    state.remaining -= 1;
+    let done = state.remaining == 0;
+    let finished = match &mut state.output {
+      Output::Buffered { request, results } => {
+        results[index] = result;
+        if done {
+          let results: Vec<JsonValue> = std::mem::take(results).into_iter().flatten().collect();
+          request.take().map(|r| (r, results))
+        } else {
+          None
+        }
+      }
+      Output::Stream(writer) => {
+        if let (Some(w), Some(mut result)) = (writer.as_mut(), result) {
+          result["index"] = index.into();
+          let mut line = serde_json::to_vec(&result).unwrap();
+          line.push(b'\n');
+          // a client that went away stops receiving results, the commands still complete
+          if w.send(&line).is_err() {
+            writer.take();
+          }
+        }
+        if done {
+          // ends the body
+          writer.take();
+        }
+        None
+      }
+    };
+    drop(state);
+
+    if let Some((request, mut results)) = finished {
+      match self.protocol {
+        Protocol::JsonRpc { .. } if results.is_empty() => {
+          // only notifications: there is nothing to answer
+          let mut r = Response::empty(204u16);
+          cors(&request, &mut r, &config.allowed_origins);
+          let _ = request.respond(r);
+        }
+        Protocol::JsonRpc { single: true } => {
+          respond_value(request, "", Ok(results.remove(0)), Vec::new(), config)
+        }
+        Protocol::Broadcast => {
+          let labels = self.ids.iter().flatten().filter_map(|id| id.as_str());
+          let results: Map<String, JsonValue> = labels.map(String::from).zip(results).collect();
+          respond_value(
+            request,
+            "",
+            Ok(JsonValue::Object(results)),
+            Vec::new(),
+            config,
+          )
+        }
+        _ => respond_value(
+          request,
+          "",
+          Ok(JsonValue::Array(results)),
+          Vec::new(),
+          config,
+        ),
+      }
+    }
+  }
+}
+
+/// Dispatches the items of a batch, given with their index, like the other invokes of their window.
+pub(crate) fn dispatch<R: Runtime>(
+  batch: &Arc<Batch>,
+  items: Vec<(usize, Window<R>, InvokePayload)>,
+  config: &Arc<Config>,
+  next_id: &AtomicUsize,
+  pending: &Arc<BatchItems>,
+  dispatcher: &Arc<Dispatcher>,
+) {
+  for (index, window, mut payload) in items {
+    if !dispatcher.admits(window.label(), config) {
+      let error = HttpError::new(
+        503,
+        ErrorCode::TooManyInvokes,
+        format!("too many invokes queued for window `{}`", window.label()),
+      );
+      batch.fail_item(index, error, config);
+      continue;
+    }
+    let id = next_id.fetch_add(1, Ordering::Relaxed);
+    payload.callback = CallbackFn(id);
+    payload.error = CallbackFn(id);
+    let item = PendingItem {
+      batch: batch.clone(),
+      index,
+      window: window.label().to_string(),
+      cmd: payload.cmd.clone(),
+      started: Instant::now(),
+    };
+    pending.lock().unwrap().insert(id, item);
+    let on_error = {
+      let (pending, config) = (pending.clone(), config.clone());
+      move |e: HttpError| {
+        let item = pending.lock().unwrap().remove(&id);
+        if let Some(item) = item {
+          item.batch.fail_item(item.index, e, &config);
+        }
+      }
+    };
+    dispatcher.dispatch(window, payload, config, on_error);
+  }
+}
+
+/// Removes the batch items whose command didn't respond within `timeout`.
+pub(crate) fn take_timed_out(pending: &BatchItems, timeout: Duration) -> Vec<(usize, PendingItem)> {
+  let mut pending = pending.lock().unwrap();
+  let ids: Vec<usize> = pending
+    .iter()
+    .filter(|(_, item)| item.started.elapsed() >= timeout)
+    .map(|(id, _)| *id)
+    .collect();
+  ids
+    .into_iter()
+    .filter_map(|id| pending.remove(&id).map(|item| (id, item)))
+    .collect()
+}
+
+/// Builds the payload of a batch item.
+pub(crate) fn item_payload(
+  cmd: String,
+  mut args: Map<String, JsonValue>,
+  invoke_key: Option<String>,
+) -> serde_json::Result<InvokePayload> {
+  args.insert("cmd".into(), cmd.into());
+  // the callbacks are assigned when dispatching the item
+  args.insert("callback".into(), 0.into());
+  args.insert("error".into(), 0.into());
+  args.insert("__TAURI_INVOKE_KEY__".into(), invoke_key.into());
+  serde_json::from_value(JsonValue::Object(args))
+}
+
+pub(crate) fn respond_error(request: Request, error: HttpError, config: &Config) {
+  let mut r = error::transport_error(&error, config.error_format);
+  cors(&request, &mut r, &config.allowed_origins);
+  let _ = request.respond(r);
+}
+
+/// Reads the items of the batch request and dispatches them.
+pub(crate) fn handle<R: Runtime>(
+  app: &AppHandle<R>,
+  mut request: Request,
+  config: &Arc<Config>,
+  next_id: &AtomicUsize,
+  pending: &Arc<BatchItems>,
+  dispatcher: &Arc<Dispatcher>,
+) {
+  let url = match request_url(&request) {
+    Ok(url) => url,
+    Err(e) => return respond_error(request, e, config),
+  };
+  let mut pieces = url.path().split('/').skip(2);
+  let window_label = match decode_segment(pieces.next().unwrap_or_default())
+    .and_then(|label| check_extra_segments(pieces, config).map(|()| label))
+  {
+    Ok(label) => label,
+    Err(e) => return respond_error(request, e, config),
+  };
+  let window = match invoke_window(app, &window_label, config) {
+    Some(window) => window,
+    None => {
+      return respond_error(
+        request,
+        HttpError::new(
+          404,
+          ErrorCode::UnknownWindow,
+          format!("window `{}` not found", window_label),
+        ),
+        config,
+      )
+    }
+  };
+  if request.method() != &Method::Post {
+    return respond_error(
+      request,
+      HttpError::method_not_allowed("POST, OPTIONS"),
+      config,
+    );
+  }
+  let invoke_key = match query_args(&url) {
+    Ok((_, invoke_key)) => invoke_key,
+    Err(e) => {
+      return respond_error(
+        request,
+        HttpError::new(400, ErrorCode::InvalidArgs, e),
+        config,
+      )
+    }
+  };
+
+  let items: Result<Vec<BatchItem>, HttpError> = body_reader(&mut request, config).and_then(|r| {
+    serde_json::from_reader(r).map_err(|e| {
+      if e.is_io() {
+        body_error(e.into())
+      } else {
+        HttpError::new(400, ErrorCode::InvalidBody, e.to_string())
+      }
+    })
+  });
+  let items = match items {
+    Ok(items) if items.is_empty() => {
+      return respond_value(
+        request,
+        "",
+        Ok(JsonValue::Array(Vec::new())),
+        Vec::new(),
+        config,
+      )
+    }
+    Ok(items) => items,
+    Err(e) => return respond_error(request, e, config),
+  };
+
+  let mut ids = Vec::with_capacity(items.len());
+  let mut payloads = Vec::with_capacity(items.len());
+  for (index, item) in items.into_iter().enumerate() {
+    let key = item.invoke_key.or_else(|| invoke_key.clone());
+    let mut payload = match item_payload(item.cmd, item.args, key) {
+      Ok(payload) => payload,
+      Err(e) => {
+        return respond_error(
+          request,
+          HttpError::new(400, ErrorCode::InvalidBody, e.to_string()),
+          config,
+        )
+      }
+    };
+    merge_header_args(&mut payload, request.headers(), config);
+    ids.push(item.callback);
+    payloads.push((index, window.clone(), payload));
+  }
+
+  let streamed = header(&request, "Accept")
+    .map(|accept| accept.to_ascii_lowercase().contains(NDJSON))
+    .unwrap_or_default();
+  let batch = Arc::new(if streamed {
+    Batch::streamed(request, ids, config)
+  } else {
+    Batch::new(request, Protocol::Batch, ids)
+  });
+  dispatch(&batch, payloads, config, next_id, pending, dispatcher);
+}
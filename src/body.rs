@@ -0,0 +1,433 @@
+// Copyright 2019-2021 Tauri Programme within The Commons Conservancy
+// SPDX-License-Identifier: Apache-2.0
+// SPDX-License-Identifier: MIT
+
+use std::{
+  cell::RefCell,
+  collections::HashMap,
+  fs::{File, OpenOptions},
+  io::{Cursor, Read, Write},
+  path::{Path, PathBuf},
+};
+
+use serde::{ser::SerializeMap, Deserialize, Serialize, Serializer};
+use serde_json::Value as JsonValue;
+
+use crate::{body_error, random_token, ErrorCode, HttpError};
+
+/// The tag of the command outputs read by the server.
+const TAG: &str = "__tauriInvokeHttp";
+
+thread_local! {
+  // Tauri serializes a command output and calls the invoke responder on the same thread,
+  // so the responder only trusts the tokens registered there, which clients can't forge
+  static RAW_RESPONSES: RefCell<HashMap<String, RawResponse>> = RefCell::new(HashMap::new());
+  static RESPONSE_HEADERS: RefCell<HashMap<String, Vec<(String, String)>>> =
+    RefCell::new(HashMap::new());
+}
+
+/// Forgets the command outputs registered on this thread once the invoke responder returns.
+pub(crate) struct RegisteredOutputs;
+
+impl Drop for RegisteredOutputs {
+  fn drop(&mut self) {
+    RAW_RESPONSES.with(|responses| responses.borrow_mut().clear());
+    RESPONSE_HEADERS.with(|headers| headers.borrow_mut().clear());
+  }
+}
+
+/// The token of a tagged command output, if it has the given tag.
+fn output_token<'a>(value: &'a JsonValue, tag: &str) -> Option<&'a str> {
+  if value.get(TAG).and_then(|v| v.as_str()) == Some(tag) {
+    value.get("token").and_then(|v| v.as_str())
+  } else {
+    None
+  }
+}
+
+/// The size of the chunks written to the spool file.
+const SPOOL_CHUNK_SIZE: u64 = 64 * 1024;
+
+/// The body of a raw invoke, sent to `POST /{window}/{cmd}` with a non JSON `Content-Type`.
+///
+/// Commands receive it in the `body` argument. Tauri 1 passes the command arguments as a JSON
+/// value, so the bytes of a body kept in memory are carried as an array of numbers, copied
+/// when the arguments are built and again when the command reads them. Only spooled bodies,
+/// passed as the path of their file, avoid the copies: lower `Invoke::spool_threshold`
+/// to spool more of them.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum RawBody {
+  /// The body was small enough to be kept in memory.
+  Bytes(Vec<u8>),
+  /// The body was spooled to a temporary file, which is removed once the command responds.
+  File {
+    /// Path of the spooled body.
+    path: PathBuf,
+  },
+}
+
+impl RawBody {
+  /// Opens a reader over the body contents.
+  pub fn reader(&self) -> std::io::Result<Box<dyn Read + '_>> {
+    match self {
+      Self::Bytes(bytes) => Ok(Box::new(Cursor::new(bytes))),
+      Self::File { path } => Ok(Box::new(File::open(path)?)),
+    }
+  }
+}
+
+/// A temporary file holding a spooled request body, removed on drop.
+pub(crate) struct SpoolFile(PathBuf);
+
+impl Drop for SpoolFile {
+  fn drop(&mut self) {
+    let _ = std::fs::remove_file(&self.0);
+  }
+}
+
+/// The error returned when a request body exceeds the configured limit.
+#[derive(Debug)]
+struct LimitExceeded;
+
+impl std::fmt::Display for LimitExceeded {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    f.write_str("the request body exceeds the size limit")
+  }
+}
+
+impl std::error::Error for LimitExceeded {}
+
+/// A reader failing with [`LimitExceeded`] once more than `remaining` bytes are read.
+pub(crate) struct LimitedReader<R> {
+  inner: R,
+  remaining: u64,
+}
+
+impl<R> LimitedReader<R> {
+  pub(crate) fn new(inner: R, limit: u64) -> Self {
+    Self {
+      inner,
+      remaining: limit,
+    }
+  }
+}
+
+impl<R: Read> Read for LimitedReader<R> {
+  fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+    let read = self.inner.read(buf)?;
+    if read as u64 > self.remaining {
+      return Err(std::io::Error::new(
+        std::io::ErrorKind::InvalidData,
+        LimitExceeded,
+      ));
+    }
+    self.remaining -= read as u64;
+    Ok(read)
+  }
+}
+
+/// Whether the error was caused by a body exceeding its limit.
+pub(crate) fn is_limit_exceeded(error: &std::io::Error) -> bool {
+  error
+    .get_ref()
+    .map(|e| e.is::<LimitExceeded>())
+    .unwrap_or_default()
+}
+
+/// Creates a temporary file readable by the current user only, with an unpredictable name
+/// ending with `.{extension}`, so other local users can neither read it nor create it first.
+pub(crate) fn create_temp_file(extension: &str) -> std::io::Result<(PathBuf, File)> {
+  let path = std::env::temp_dir().join(format!(
+    "tauri-invoke-http-{}-{}.{}",
+    std::process::id(),
+    random_token(),
+    extension
+  ));
+  let mut options = OpenOptions::new();
+  options.write(true).create_new(true);
+  #[cfg(unix)]
+  std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+  let file = options.open(&path)?;
+  Ok((path, file))
+}
+
+/// Reads a raw request body, spooling it to a temporary file when it exceeds `threshold` bytes.
+///
+/// The buffer is sized after the `Content-Length` of the request, if any, so the body is read
+/// without growing it. Failing to read the body is the client's fault, unlike failing to spool it.
+pub(crate) fn read_raw(
+  reader: &mut dyn Read,
+  threshold: usize,
+  length: Option<usize>,
+) -> Result<(RawBody, Option<SpoolFile>), HttpError> {
+  // the length is untrusted, so bodies to be spooled don't get a larger buffer
+  let mut buffer = Vec::with_capacity(length.unwrap_or_default().min(threshold + 1));
+  reader
+    .take(threshold as u64 + 1)
+    .read_to_end(&mut buffer)
+    .map_err(body_error)?;
+  if buffer.len() <= threshold {
+    return Ok((RawBody::Bytes(buffer), None));
+  }
+
+  let spool_error = |e: std::io::Error| {
+    HttpError::new(
+      500,
+      ErrorCode::InternalError,
+      format!("failed to spool the request body: {}", e),
+    )
+  };
+  let (path, mut file) = create_temp_file("body").map_err(spool_error)?;
+  let spool = SpoolFile(path.clone());
+  loop {
+    file.write_all(&buffer).map_err(spool_error)?;
+    buffer.clear();
+    reader
+      .take(SPOOL_CHUNK_SIZE)
+      .read_to_end(&mut buffer)
+      .map_err(body_error)?;
+    if buffer.is_empty() {
+      break;
+    }
+  }
+  Ok((RawBody::File { path }, Some(spool)))
+}
+
+/// A raw response body streamed to the HTTP client from a file, instead of a JSON value.
+///
+/// Return it from a command to avoid holding large outputs in memory.
+/// It is serialized as an opaque token, only valid for the invoke responder of the server.
+#[derive(Debug, Clone)]
+pub struct RawResponse {
+  path: PathBuf,
+  content_type: Option<String>,
+  remove_file: bool,
+  attachment: Option<String>,
+}
+
+impl Serialize for RawResponse {
+  fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+    let token = random_token();
+    RAW_RESPONSES.with(|responses| responses.borrow_mut().insert(token.clone(), self.clone()));
+    let mut map = serializer.serialize_map(Some(2))?;
+    map.serialize_entry(TAG, "raw")?;
+    map.serialize_entry("token", &token)?;
+    map.end()
+  }
+}
+
+impl RawResponse {
+  /// Streams the contents of the file at the given path.
+  pub fn file(path: impl Into<PathBuf>) -> Self {
+    Self {
+      path: path.into(),
+      content_type: None,
+      remove_file: false,
+      attachment: None,
+    }
+  }
+
+  /// Streams the file at the given path as a download named `filename`,
+  /// using the `Content-Disposition: attachment` header.
+  pub fn download(path: impl Into<PathBuf>, filename: impl Into<String>) -> Self {
+    let mut response = Self::file(path);
+    response.attachment.replace(filename.into());
+    response
+  }
+
+  /// Sets the `Content-Type` of the response.
+  /// Defaults to a type guessed from the file extension, or `application/octet-stream`.
+  pub fn content_type(mut self, content_type: impl Into<String>) -> Self {
+    self.content_type.replace(content_type.into());
+    self
+  }
+
+  /// Removes the file once the response is sent, useful for temporary files.
+  pub fn remove_file(mut self) -> Self {
+    self.remove_file = true;
+    self
+  }
+
+  /// Reads a [`RawResponse`] from a command output serialized on this thread.
+  pub(crate) fn from_value(value: &JsonValue) -> Option<Self> {
+    let token = output_token(value, "raw")?;
+    RAW_RESPONSES.with(|responses| responses.borrow_mut().remove(token))
+  }
+
+  pub(crate) fn path(&self) -> &Path {
+    &self.path
+  }
+
+  pub(crate) fn content_type_or_default(&self) -> &str {
+    if let Some(content_type) = &self.content_type {
+      return content_type;
+    }
+    let extension = self
+      .path
+      .extension()
+      .and_then(|e| e.to_str())
+      .unwrap_or_default()
+      .to_ascii_lowercase();
+    match extension.as_str() {
+      "txt" => "text/plain",
+      "csv" => "text/csv",
+      "html" | "htm" => "text/html",
+      "json" => "application/json",
+      "pdf" => "application/pdf",
+      "zip" => "application/zip",
+      "png" => "image/png",
+      "jpg" | "jpeg" => "image/jpeg",
+      "gif" => "image/gif",
+      "svg" => "image/svg+xml",
+      "mp3" => "audio/mpeg",
+      "mp4" => "video/mp4",
+      "webm" => "video/webm",
+      _ => "application/octet-stream",
+    }
+  }
+
+  /// The `Content-Disposition` header value of download responses.
+  pub(crate) fn content_disposition(&self) -> Option<String> {
+    self.attachment.as_ref().map(|filename| {
+      let fallback: String = filename
+        .chars()
+        .map(|c| {
+          if c.is_ascii() && !c.is_ascii_control() && c != '"' && c != '\\' {
+            c
+          } else {
+            '_'
+          }
+        })
+        .collect();
+      let mut encoded = String::new();
+      for b in filename.bytes() {
+        if b.is_ascii_alphanumeric() || b"!#$&+-.^_`|~".contains(&b) {
+          encoded.push(b as char);
+        } else {
+          encoded.push_str(&format!("%{:02X}", b));
+        }
+      }
+      format!(
+        "attachment; filename=\"{}\"; filename*=UTF-8''{}",
+        fallback, encoded
+      )
+    })
+  }
+
+  pub(crate) fn should_remove_file(&self) -> bool {
+    self.remove_file
+  }
+}
+
+/// Parses a `Range` header over a body of `len` bytes into a `start..end` byte range.
+///
+/// Returns `Ok(None)` when the range should be ignored (multiple or non byte ranges)
+/// and `Err(())` when it can't be satisfied.
+pub(crate) fn parse_range(range: &str, len: u64) -> Result<Option<(u64, u64)>, ()> {
+  let spec = match range.trim().strip_prefix("bytes=") {
+    Some(spec) if !spec.contains(',') => spec.trim(),
+    _ => return Ok(None),
+  };
+  let (start, end) = match spec.split_once('-') {
+    Some(bounds) => bounds,
+    None => return Ok(None),
+  };
+  let (start, end) = match (start.parse::<u64>(), end.parse::<u64>()) {
+    (Ok(start), Ok(end)) if start <= end => (start, end.saturating_add(1).min(len)),
+    (Ok(start), Err(_)) if end.is_empty() => (start, len),
+    (Err(_), Ok(suffix)) if start.is_empty() && suffix > 0 => (len.saturating_sub(suffix), len),
+    _ => return Ok(None),
+  };
+  if start >= len {
+    return Err(());
+  }
+  Ok(Some((start, end)))
+}
+
+/// A command response carrying HTTP headers for the client.
+///
+/// Only the headers allowed with `Invoke::response_headers` are forwarded.
+#[derive(Debug, Clone)]
+pub struct WithHeaders<T> {
+  body: T,
+  headers: Vec<(String, String)>,
+}
+
+impl<T: Serialize> Serialize for WithHeaders<T> {
+  fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+    let token = random_token();
+    RESPONSE_HEADERS.with(|headers| {
+      headers
+        .borrow_mut()
+        .insert(token.clone(), self.headers.clone())
+    });
+    let mut map = serializer.serialize_map(Some(3))?;
+    map.serialize_entry(TAG, "headers")?;
+    map.serialize_entry("token", &token)?;
+    map.serialize_entry("body", &self.body)?;
+    map.end()
+  }
+}
+
+impl<T> WithHeaders<T> {
+  /// Wraps the command response body.
+  pub fn new(body: T) -> Self {
+    Self {
+      body,
+      headers: Vec::new(),
+    }
+  }
+
+  /// Adds a header to the response.
+  pub fn header(mut self, name: impl Into<String>, value: impl Into<String>) -> Self {
+    self.headers.push((name.into(), value.into()));
+    self
+  }
+}
+
+/// Splits a [`WithHeaders`] command output serialized on this thread into its body and headers.
+pub(crate) fn split_headers(value: JsonValue) -> (JsonValue, Vec<(String, String)>) {
+  let headers = output_token(&value, "headers")
+    .and_then(|token| RESPONSE_HEADERS.with(|headers| headers.borrow_mut().remove(token)));
+  match (value, headers) {
+    (JsonValue::Object(mut map), Some(headers)) => {
+      (map.remove("body").unwrap_or_default(), headers)
+    }
+    (value, _) => (value, Vec::new()),
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn parses_byte_ranges() {
+    assert_eq!(parse_range("bytes=0-99", 1000), Ok(Some((0, 100))));
+    assert_eq!(parse_range("bytes=500-", 1000), Ok(Some((500, 1000))));
+    assert_eq!(parse_range("bytes=-100", 1000), Ok(Some((900, 1000))));
+    assert_eq!(parse_range(" bytes= 10-19 ", 1000), Ok(Some((10, 20))));
+  }
+
+  #[test]
+  fn clamps_ranges_to_the_body() {
+    assert_eq!(parse_range("bytes=900-1999", 1000), Ok(Some((900, 1000))));
+    assert_eq!(parse_range("bytes=-2000", 1000), Ok(Some((0, 1000))));
+  }
+
+  #[test]
+  fn ignores_unsupported_ranges() {
+    assert_eq!(parse_range("bytes=0-9,20-29", 1000), Ok(None));
+    assert_eq!(parse_range("items=0-9", 1000), Ok(None));
+    assert_eq!(parse_range("bytes=9-0", 1000), Ok(None));
+    assert_eq!(parse_range("bytes=-0", 1000), Ok(None));
+    assert_eq!(parse_range("bytes=a-b", 1000), Ok(None));
+  }
+
+  #[test]
+  fn rejects_unsatisfiable_ranges() {
+    assert_eq!(parse_range("bytes=1000-", 1000), Err(()));
+    assert_eq!(parse_range("bytes=0-0", 0), Err(()));
+  }
+}
@@ -0,0 +1,160 @@
+// Copyright 2019-2021 Tauri Programme within The Commons Conservancy
+// SPDX-License-Identifier: Apache-2.0
+// SPDX-License-Identifier: MIT
+
+//! Invokes sent to every window on `POST /__all/{cmd}`.
+//!
+//! The body is the object of command arguments, and the response maps the label of every window
+//! to its `{ status, result | error }`, once all of them have responded.
+
+use std::sync::{atomic::AtomicUsize, Arc};
+
+use serde_json::{Map, Value as JsonValue};
+use tauri::{AppHandle, Manager, Runtime};
+use tiny_http::{Method, Request};
+
+use crate::{
+  batch::{dispatch, item_payload, respond_error, Batch, BatchItems, Protocol},
+  body_error, body_reader, check_extra_segments, command, decode_segment,
+  dispatch::Dispatcher,
+  merge_header_args, query_args, request_url, respond_value, window_reachable, Config, ErrorCode,
+  HttpError,
+};
+
+/// Reads the command of a `/__all/{cmd}` path, decoded like the commands of `/{window}/{cmd}`.
+fn broadcast_command(path: &str, config: &Config) -> Result<String, HttpError> {
+  let mut pieces = path.split('/').skip(2);
+  let cmd = decode_segment(pieces.next().unwrap_or_default())?;
+  let cmd = command(cmd, &mut pieces)?;
+  check_extra_segments(pieces, config)?;
+  Ok(cmd)
+}
+
+/// Reads the invoke and dispatches it to every window.
+pub(crate) fn handle<R: Runtime>(
+  app: &AppHandle<R>,
+  mut request: Request,
+  config: &Arc<Config>,
+  next_id: &AtomicUsize,
+  pending: &Arc<BatchItems>,
+  dispatcher: &Arc<Dispatcher>,
+) {
+  if request.method() != &Method::Post {
+    return respond_error(
+      request,
+      HttpError::method_not_allowed("POST, OPTIONS"),
+      config,
+    );
+  }
+  let url = match request_url(&request) {
+    Ok(url) => url,
+    Err(e) => return respond_error(request, e, config),
+  };
+  let cmd = match broadcast_command(url.path(), config) {
+    Ok(cmd) => cmd,
+    Err(e) => return respond_error(request, e, config),
+  };
+  let invoke_key = match query_args(&url) {
+    Ok((_, invoke_key)) => invoke_key,
+    Err(e) => {
+      return respond_error(
+        request,
+        HttpError::new(400, ErrorCode::InvalidArgs, e),
+        config,
+      )
+    }
+  };
+
+  let args: Result<JsonValue, HttpError> = body_reader(&mut request, config).and_then(|r| {
+    serde_json::from_reader(r).map_err(|e| {
+      if e.is_io() {
+        body_error(e.into())
+      } else {
+        HttpError::new(400, ErrorCode::InvalidBody, e.to_string())
+      }
+    })
+  });
+  let mut args = match args {
+    Ok(JsonValue::Object(args)) => args,
+    Ok(JsonValue::Null) => Map::new(),
+    Ok(_) => {
+      let error = HttpError::new(
+        400,
+        ErrorCode::InvalidBody,
+        "expected an object of arguments",
+      );
+      return respond_error(request, error, config);
+    }
+    Err(e) => return respond_error(request, e, config),
+  };
+  let invoke_key = match args.remove("__TAURI_INVOKE_KEY__") {
+    Some(JsonValue::String(key)) => Some(key),
+    _ => invoke_key,
+  };
+
+  let mut windows = app.windows();
+  windows.retain(|label, _| window_reachable(label, config));
+  if windows.is_empty() {
+    return respond_value(request, "", Ok(Map::new().into()), Vec::new(), config);
+  }
+  let mut labels = Vec::with_capacity(windows.len());
+  let mut payloads = Vec::with_capacity(windows.len());
+  for (index, (label, window)) in windows.into_iter().enumerate() {
+    let mut payload = match item_payload(cmd.clone(), args.clone(), invoke_key.clone()) {
+      Ok(payload) => payload,
+      Err(e) => {
+        let error = HttpError::new(400, ErrorCode::InvalidBody, e.to_string());
+        return respond_error(request, error, config);
+      }
+    };
+    merge_header_args(&mut payload, request.headers(), config);
+    labels.push(Some(label.into()));
+    payloads.push((index, window, payload));
+  }
+
+  let batch = Arc::new(Batch::new(request, Protocol::Broadcast, labels));
+  dispatch(&batch, payloads, config, next_id, pending, dispatcher);
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn decodes_the_command() {
+    let config = Config::default();
+    assert_eq!(broadcast_command("/__all/greet", &config).unwrap(), "greet");
+    assert_eq!(
+      broadcast_command("/__all/save%20all", &config).unwrap(),
+      "save all"
+    );
+  }
+
+  #[test]
+  fn reads_plugin_commands() {
+    let config = Config::default();
+    assert_eq!(
+      broadcast_command("/__all/plugin/store/set", &config).unwrap(),
+      "plugin:store|set"
+    );
+  }
+
+  #[test]
+  fn rejects_extra_segments_in_strict_mode() {
+    let mut config = Config::default();
+    assert_eq!(
+      broadcast_command("/__all/greet/extra", &config).unwrap(),
+      "greet"
+    );
+    config.strict_paths = true;
+    let error = broadcast_command("/__all/greet/extra", &config).unwrap_err();
+    assert_eq!(error.status, 404);
+    assert_eq!(error.code, ErrorCode::UnexpectedPathSegment);
+  }
+
+  #[test]
+  fn rejects_invalid_encodings() {
+    let error = broadcast_command("/__all/%FF", &Config::default()).unwrap_err();
+    assert_eq!(error.code, ErrorCode::InvalidUrl);
+  }
+}
@@ -0,0 +1,73 @@
+// Copyright 2019-2021 Tauri Programme within The Commons Conservancy
+// SPDX-License-Identifier: Apache-2.0
+// SPDX-License-Identifier: MIT
+
+//! The buffers responses are serialized into, reused once the response is sent.
+//!
+//! `tiny_http` takes the response body, so it's wrapped in a reader giving the buffer back
+//! to the pool of the thread when dropped.
+
+use std::{
+  cell::RefCell,
+  io::{Cursor, Read},
+};
+
+use tiny_http::{Response, StatusCode};
+
+/// The number of buffers kept by each thread.
+const POOL_SIZE: usize = 16;
+/// Larger buffers are freed instead of pooled, so a single large response doesn't pin its memory.
+const MAX_CAPACITY: usize = 256 * 1024;
+
+thread_local! {
+  static POOL: RefCell<Vec<Vec<u8>>> = const { RefCell::new(Vec::new()) };
+}
+
+/// Returns an empty buffer, reusing a pooled one if any.
+pub(crate) fn take() -> Vec<u8> {
+  POOL
+    .with(|pool| pool.borrow_mut().pop())
+    .unwrap_or_default()
+}
+
+/// Returns a buffer to the pool of the thread.
+pub(crate) fn give_back(mut buffer: Vec<u8>) {
+  if buffer.capacity() > MAX_CAPACITY {
+    return;
+  }
+  buffer.clear();
+  // the pool may be gone if the thread is exiting
+  let _ = POOL.try_with(|pool| {
+    let mut pool = pool.borrow_mut();
+    if pool.len() < POOL_SIZE {
+      pool.push(buffer);
+    }
+  });
+}
+
+/// A response body returning its buffer to the pool once sent.
+pub(crate) struct PooledBody(Cursor<Vec<u8>>);
+
+impl Read for PooledBody {
+  fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+    self.0.read(buf)
+  }
+}
+
+impl Drop for PooledBody {
+  fn drop(&mut self) {
+    give_back(std::mem::take(self.0.get_mut()));
+  }
+}
+
+/// The response with the given body, like `Response::from_data`.
+pub(crate) fn response(data: Vec<u8>) -> Response<PooledBody> {
+  let length = data.len();
+  Response::new(
+    StatusCode(200),
+    Vec::new(),
+    PooledBody(Cursor::new(data)),
+    Some(length),
+    None,
+  )
+}
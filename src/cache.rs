@@ -0,0 +1,233 @@
+// Copyright 2019-2021 Tauri Programme within The Commons Conservancy
+// SPDX-License-Identifier: Apache-2.0
+// SPDX-License-Identifier: MIT
+
+//! Caching of command responses.
+
+use std::{
+  collections::{hash_map::DefaultHasher, BTreeMap, HashMap},
+  hash::{Hash, Hasher},
+  sync::Mutex,
+  time::{Duration, Instant},
+};
+
+use serde::{Serialize, Serializer};
+use serde_json::Value as JsonValue;
+use tiny_http::Header;
+
+/// Identifies the responses that can be reused: the window, the command and its serialized arguments.
+pub(crate) type CacheKey = (String, String, String);
+
+/// Serializes a JSON value with its object keys sorted at any depth, as the objects keep
+/// their insertion order with the `preserve_order` feature of `serde_json`.
+struct Canonical<'a>(&'a JsonValue);
+
+impl Serialize for Canonical<'_> {
+  fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+    match self.0 {
+      JsonValue::Object(object) => serializer.collect_map(
+        object
+          .iter()
+          .map(|(key, value)| (key, Canonical(value)))
+          .collect::<BTreeMap<_, _>>(),
+      ),
+      JsonValue::Array(array) => serializer.collect_seq(array.iter().map(Canonical)),
+      value => value.serialize(serializer),
+    }
+  }
+}
+
+/// Computes the cache key of an invoke, equal for the arguments differing by their key order.
+pub(crate) fn cache_key(window: &str, cmd: &str, args: &JsonValue) -> CacheKey {
+  let args = serde_json::to_string(&Canonical(args)).unwrap();
+  (window.into(), cmd.into(), args)
+}
+
+struct CachedResponse {
+  value: JsonValue,
+  headers: Vec<Header>,
+  expires_at: Instant,
+}
+
+/// The successful responses of the commands configured with a time to live.
+#[derive(Default)]
+pub(crate) struct ResponseCache {
+  entries: Mutex<HashMap<CacheKey, CachedResponse>>,
+}
+
+impl ResponseCache {
+  /// Returns the cached response and headers if they haven't expired.
+  pub(crate) fn get(&self, key: &CacheKey) -> Option<(JsonValue, Vec<Header>)> {
+    let mut entries = self.entries.lock().unwrap();
+    match entries.get(key) {
+      Some(entry) if entry.expires_at > Instant::now() => {
+        Some((entry.value.clone(), entry.headers.clone()))
+      }
+      Some(_) => {
+        entries.remove(key);
+        None
+      }
+      None => None,
+    }
+  }
+
+  /// Stores a response for `ttl`, evicting the expired ones.
+  pub(crate) fn insert(
+    &self,
+    key: CacheKey,
+    value: JsonValue,
+    headers: Vec<Header>,
+    ttl: Duration,
+  ) {
+    let now = Instant::now();
+    let mut entries = self.entries.lock().unwrap();
+    entries.retain(|_, entry| entry.expires_at > now);
+    entries.insert(
+      key,
+      CachedResponse {
+        value,
+        headers,
+        expires_at: now + ttl,
+      },
+    );
+  }
+}
+
+/// Computes the entity tag of a serialized response.
+///
+/// The tag is weak since the same response can be sent with different content encodings.
+pub(crate) fn etag(body: &[u8], content_type: &str) -> String {
+  let mut hasher = DefaultHasher::new();
+  content_type.hash(&mut hasher);
+  body.hash(&mut hasher);
+  format!("W/\"{:016x}\"", hasher.finish())
+}
+
+/// Whether the `If-None-Match` request header matches the entity tag, using the weak comparison.
+pub(crate) fn none_match(if_none_match: &str, etag: &str) -> bool {
+  let opaque = |tag: &str| tag.trim().trim_start_matches("W/").to_string();
+  if_none_match
+    .split(',')
+    .any(|tag| tag.trim() == "*" || opaque(tag) == opaque(etag))
+}
+
+/// A command outcome stored to answer retries of an idempotent request.
+pub(crate) type StoredResponse = (Result<JsonValue, JsonValue>, Vec<Header>);
+
+struct IdempotentRequest {
+  fingerprint: CacheKey,
+  /// Unset while the command is running.
+  response: Option<StoredResponse>,
+  expires_at: Option<Instant>,
+}
+
+/// What to do with a request carrying an `Idempotency-Key`.
+pub(crate) enum Idempotency {
+  /// The key is new: run the command.
+  New,
+  /// The request was already answered with this response.
+  Replay(StoredResponse),
+  /// A request with the same key is still running.
+  InProgress,
+  /// The key was used by a request with a different command or arguments.
+  Mismatch,
+}
+
+/// The responses of the requests sent with an `Idempotency-Key` header, by window and key.
+#[derive(Default)]
+pub(crate) struct IdempotencyStore {
+  entries: Mutex<HashMap<(String, String), IdempotentRequest>>,
+}
+
+impl IdempotencyStore {
+  /// Registers a request, returning the stored response of a previous request with the same key.
+  pub(crate) fn begin(&self, key: (String, String), fingerprint: CacheKey) -> Idempotency {
+    let now = Instant::now();
+    let mut entries = self.entries.lock().unwrap();
+    entries.retain(|_, entry| entry.expires_at.map(|e| e > now).unwrap_or(true));
+    match entries.get(&key) {
+      Some(entry) if entry.fingerprint != fingerprint => Idempotency::Mismatch,
+      Some(entry) => match &entry.response {
+        Some(response) => Idempotency::Replay(response.clone()),
+        None => Idempotency::InProgress,
+      },
+      None => {
+        entries.insert(
+          key,
+          IdempotentRequest {
+            fingerprint,
+            response: None,
+            expires_at: None,
+          },
+        );
+        Idempotency::New
+      }
+    }
+  }
+
+  /// Stores the response of a request for `ttl`.
+  pub(crate) fn complete(&self, key: &(String, String), response: StoredResponse, ttl: Duration) {
+    if let Some(entry) = self.entries.lock().unwrap().get_mut(key) {
+      entry.response.replace(response);
+      entry.expires_at.replace(Instant::now() + ttl);
+    }
+  }
+
+  /// Forgets a request whose response can't be replayed, so retries run the command again.
+  pub(crate) fn abandon(&self, key: &(String, String)) {
+    self.entries.lock().unwrap().remove(key);
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use serde_json::json;
+
+  use super::*;
+
+  #[test]
+  fn ignores_the_key_order() {
+    let a =
+      json!({ "query": "tauri", "page": { "size": 10, "index": 2 }, "tags": [{ "b": 1, "a": 2 }] });
+    let b =
+      json!({ "tags": [{ "a": 2, "b": 1 }], "page": { "index": 2, "size": 10 }, "query": "tauri" });
+    assert_eq!(
+      cache_key("main", "search", &a),
+      cache_key("main", "search", &b)
+    );
+  }
+
+  #[test]
+  fn tells_invokes_apart() {
+    let args = json!({ "query": "tauri" });
+    let key = cache_key("main", "search", &args);
+    assert_ne!(key, cache_key("other", "search", &args));
+    assert_ne!(key, cache_key("main", "find", &args));
+    assert_ne!(key, cache_key("main", "search", &json!({ "query": "wry" })));
+    // the array order matters
+    assert_ne!(
+      cache_key("main", "sum", &json!({ "values": [1, 2] })),
+      cache_key("main", "sum", &json!({ "values": [2, 1] }))
+    );
+  }
+
+  #[test]
+  fn tags_the_body_and_content_type() {
+    let tag = etag(b"{}", "application/json");
+    assert!(tag.starts_with("W/\"") && tag.ends_with('"'), "{}", tag);
+    assert_eq!(tag, etag(b"{}", "application/json"));
+    assert_ne!(tag, etag(b"[]", "application/json"));
+    assert_ne!(tag, etag(b"{}", "application/msgpack"));
+  }
+
+  #[test]
+  fn matches_if_none_match_weakly() {
+    let tag = "W/\"0123456789abcdef\"";
+    assert!(none_match(tag, tag));
+    assert!(none_match("\"0123456789abcdef\"", tag));
+    assert!(none_match("\"other\", W/\"0123456789abcdef\"", tag));
+    assert!(none_match(" * ", tag));
+    assert!(!none_match("W/\"other\"", tag));
+    assert!(!none_match("", tag));
+  }
+}
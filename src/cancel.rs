@@ -0,0 +1,222 @@
+// Copyright 2019-2021 Tauri Programme within The Commons Conservancy
+// SPDX-License-Identifier: Apache-2.0
+// SPDX-License-Identifier: MIT
+
+//! Cancellation of running invokes, as Tauri 1 commands can't tell their client is gone.
+//!
+//! The client sends a `"__CANCEL__"` argument, received by the command as a [`Cancellation`].
+//! Invokes accepting `application/x-ndjson` are streamed, and cancelled once the client disconnects.
+//! Invokes sent with a `Tauri-Invoke-Id` header are cancelled by `DELETE /__invoke/{id}`, with their
+//! `__TAURI_INVOKE_KEY__`.
+
+use std::{
+  collections::HashMap,
+  sync::{Arc, Mutex},
+};
+
+use serde::{de::Error as _, Deserialize, Deserializer};
+use serde_json::Value as JsonValue;
+use tauri::{InvokePayload, Manager, Runtime};
+use tiny_http::{Method, Request, Response};
+
+use crate::{
+  batch::respond_error, channel::ChannelStreams, cors, inspector::token_matches, request_url,
+  Config, ErrorCode, HttpError,
+};
+
+const MARKER: &str = "__CANCEL__";
+
+/// A command argument telling whether the invoke was cancelled.
+///
+/// ```rust,ignore
+/// #[tauri::command]
+/// fn search(app: tauri::AppHandle, query: String, cancel: tauri_invoke_http::Cancellation) {
+///   for chunk in index_chunks() {
+///     if cancel.is_cancelled(&app) {
+///       return;
+///     }
+///     search_chunk(chunk, &query);
+///   }
+/// }
+/// ```
+#[derive(Debug, Clone)]
+pub struct Cancellation {
+  /// Unset for invokes that can't be cancelled, e.g. batch items.
+  invoke_id: Option<usize>,
+}
+
+impl<'de> Deserialize<'de> for Cancellation {
+  fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+    let value = String::deserialize(deserializer)?;
+    if value == MARKER {
+      return Ok(Self { invoke_id: None });
+    }
+    value
+      .strip_prefix(MARKER)
+      .and_then(|id| id.strip_prefix(':'))
+      .and_then(|id| id.parse().ok())
+      .map(|id| Self {
+        invoke_id: Some(id),
+      })
+      .ok_or_else(|| D::Error::custom(format!("invalid cancellation `{}`", value)))
+  }
+}
+
+impl Cancellation {
+  /// Whether the client of the invoke is gone or cancelled it.
+  pub fn is_cancelled<R: Runtime, M: Manager<R>>(&self, manager: &M) -> bool {
+    let id = match self.invoke_id {
+      Some(id) => id,
+      None => return false,
+    };
+    manager
+      .try_state::<Arc<Cancellations>>()
+      .map_or(false, |cancellations| cancellations.is_cancelled(id))
+      || manager
+        .try_state::<Arc<ChannelStreams>>()
+        .map_or(false, |streams| streams.is_closed(id))
+  }
+}
+
+pub(crate) fn is_cancellation(value: &JsonValue) -> bool {
+  value.as_str().map_or(false, |v| v.starts_with(MARKER))
+}
+
+struct Invoke {
+  client_id: Option<String>,
+  /// The `__TAURI_INVOKE_KEY__` of the invoke, required to cancel it by its client id.
+  invoke_key: String,
+  cancelled: bool,
+}
+
+#[derive(Default)]
+struct Registry {
+  /// The running invokes by client id.
+  ids: HashMap<String, usize>,
+  /// The running invokes by callback id.
+  invokes: HashMap<usize, Invoke>,
+}
+
+/// The cancellable invokes, managed by the app so [`Cancellation::is_cancelled`] can reach them.
+#[derive(Default)]
+pub(crate) struct Cancellations {
+  registry: Mutex<Registry>,
+}
+
+impl Cancellations {
+  /// Binds the cancellation arguments of the invoke to it, so clients can't observe other invokes,
+  /// registering the invoke under the `Tauri-Invoke-Id` of its client.
+  pub(crate) fn bind(
+    &self,
+    payload: &mut InvokePayload,
+    invoke_id: usize,
+    client_id: Option<&str>,
+  ) {
+    let args = match payload.inner.as_object_mut() {
+      Some(args) => args,
+      None => return,
+    };
+    let mut cancellable = false;
+    for value in args.values_mut().filter(|v| is_cancellation(v)) {
+      *value = format!("{}:{}", MARKER, invoke_id).into();
+      cancellable = true;
+    }
+    if !cancellable {
+      return;
+    }
+    let mut registry = self.registry.lock().unwrap();
+    if let Some(client_id) = client_id {
+      if let Some(previous) = registry.ids.insert(client_id.to_string(), invoke_id) {
+        registry.invokes.remove(&previous);
+      }
+    }
+    let invoke = Invoke {
+      client_id: client_id.map(Into::into),
+      invoke_key: payload.invoke_key.clone().unwrap_or_default(),
+      cancelled: false,
+    };
+    registry.invokes.insert(invoke_id, invoke);
+  }
+
+  fn is_cancelled(&self, invoke_id: usize) -> bool {
+    let registry = self.registry.lock().unwrap();
+    registry
+      .invokes
+      .get(&invoke_id)
+      .map_or(false, |invoke| invoke.cancelled)
+  }
+
+  /// Cancels a running invoke, such as the invoke of a deleted job.
+  pub(crate) fn cancel(&self, invoke_id: usize) {
+    if let Some(invoke) = self.registry.lock().unwrap().invokes.get_mut(&invoke_id) {
+      invoke.cancelled = true;
+    }
+  }
+
+  /// Forgets the invoke once it responded.
+  pub(crate) fn finish(&self, invoke_id: usize) {
+    let mut registry = self.registry.lock().unwrap();
+    if let Some(Invoke {
+      client_id: Some(client_id),
+      ..
+    }) = registry.invokes.remove(&invoke_id)
+    {
+      registry.ids.remove(&client_id);
+    }
+  }
+
+  /// Handles a request to the `/__invoke/{id}` route.
+  pub(crate) fn handle(&self, request: Request, config: &Config) {
+    if request.method() != &Method::Delete {
+      return respond_error(
+        request,
+        HttpError::method_not_allowed("DELETE, OPTIONS"),
+        config,
+      );
+    }
+    let url = match request_url(&request) {
+      Ok(url) => url,
+      Err(e) => return respond_error(request, e, config),
+    };
+    let id = url.path().trim_start_matches("/__invoke/").to_string();
+    let invoke_key = url
+      .query_pairs()
+      .find(|(key, _)| key == "__TAURI_INVOKE_KEY__")
+      .map(|(_, value)| value.into_owned());
+    let mut registry = self.registry.lock().unwrap();
+    let invoke = registry.ids.get(&id).copied();
+    let result = match invoke.and_then(|invoke| registry.invokes.get_mut(&invoke)) {
+      None => Err(HttpError::new(
+        404,
+        ErrorCode::UnknownInvoke,
+        format!("invoke `{}` not found", id),
+      )),
+      // an invoke is only cancelled with the key it was sent with
+      Some(invoke) => match invoke_key.as_deref() {
+        Some(sent) if token_matches(sent, &invoke.invoke_key) => {
+          invoke.cancelled = true;
+          Ok(())
+        }
+        Some(_) => Err(HttpError::new(
+          403,
+          ErrorCode::InvalidInvokeKey,
+          "the `__TAURI_INVOKE_KEY__` of the request is not the one of the invoke",
+        )),
+        None => Err(HttpError::new(
+          401,
+          ErrorCode::MissingInvokeKey,
+          "the request has no `__TAURI_INVOKE_KEY__`",
+        )),
+      },
+    };
+    drop(registry);
+    match result {
+      Ok(()) => {
+        let mut r = Response::empty(204u16);
+        cors(&request, &mut r, &config.allowed_origins);
+        let _ = request.respond(r);
+      }
+      Err(e) => respond_error(request, e, config),
+    }
+  }
+}
@@ -0,0 +1,239 @@
+// Copyright 2019-2021 Tauri Programme within The Commons Conservancy
+// SPDX-License-Identifier: Apache-2.0
+// SPDX-License-Identifier: MIT
+
+//! Incremental command messages for HTTP clients, as Tauri 1 has no `ipc::Channel`.
+//!
+//! The client sends a `"__CHANNEL__:{id}"` argument, received by the command as a [`Channel`].
+//! Invokes accepting `application/x-ndjson` receive the messages in the response, before the
+//! command output, and for other clients the messages are triggered as the `channel:{id}` event
+//! they can subscribe to on the event stream.
+//!
+//! Invokes taking a [`crate::Cancellation`] are streamed too, so their client leaving is noticed.
+
+use std::{
+  collections::HashMap,
+  sync::{Arc, Mutex},
+};
+
+use serde::{de::Error as _, Deserialize, Deserializer, Serialize};
+use serde_json::{json, Value as JsonValue};
+use tauri::{InvokePayload, Manager, Runtime};
+use tiny_http::{Header, Request, Response};
+
+use crate::{
+  cancel, cors, error_status,
+  events::valid_event_name,
+  header,
+  stream::{BufferedWriter, ChunkedWriter},
+  Config, ErrorCode,
+};
+
+const PREFIX: &str = "__CHANNEL__:";
+/// The media type of streamed channel messages.
+const NDJSON: &str = "application/x-ndjson";
+
+/// What to do with the messages of a stream when the client doesn't keep up
+/// and its buffer is full, see `Invoke::backpressure`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Backpressure {
+  /// Block the sender until the client catches up. Event stream and WebSocket clients,
+  /// sharing their sender, drop the oldest message instead.
+  Block,
+  /// Drop the oldest buffered message.
+  DropOldest,
+  /// Close the stream.
+  Close,
+}
+
+impl Default for Backpressure {
+  fn default() -> Self {
+    Self::Block
+  }
+}
+
+/// The state of a channel after sending a message.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChannelState {
+  /// The message was buffered.
+  Open,
+  /// The message was buffered, dropping the oldest one as the client doesn't keep up.
+  Lagging,
+  /// The client is gone or didn't keep up, the message was dropped.
+  Closed,
+}
+
+/// A command argument sending messages to the HTTP client while the command runs.
+///
+/// ```rust,ignore
+/// #[tauri::command]
+/// fn download(app: tauri::AppHandle, on_progress: tauri_invoke_http::Channel) {
+///   for i in 0..100 {
+///     on_progress.send(&app, i).unwrap();
+///   }
+/// }
+/// ```
+#[derive(Debug, Clone)]
+pub struct Channel {
+  id: String,
+}
+
+impl<'de> Deserialize<'de> for Channel {
+  fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+    let value = String::deserialize(deserializer)?;
+    match value.strip_prefix(PREFIX) {
+      Some(id) if valid_event_name(id) => Ok(Self { id: id.into() }),
+      _ => Err(D::Error::custom(format!("invalid channel `{}`", value))),
+    }
+  }
+}
+
+impl Channel {
+  /// The id of the channel, the messages being triggered as the `channel:{id}` event.
+  pub fn id(&self) -> &str {
+    &self.id
+  }
+
+  /// Sends a message to the client, returning the state of the channel.
+  ///
+  /// Commands can stop producing messages once the channel is [`ChannelState::Closed`].
+  pub fn send<R: Runtime, M: Manager<R>, S: Serialize>(
+    &self,
+    manager: &M,
+    message: S,
+  ) -> tauri::Result<ChannelState> {
+    let message = serde_json::to_value(&message)?;
+    let streamed = manager
+      .try_state::<Arc<ChannelStreams>>()
+      .and_then(|streams| streams.send(&self.id, &message));
+    if let Some(state) = streamed {
+      return Ok(state);
+    }
+    manager.trigger_global(&format!("channel:{}", self.id), Some(message.to_string()));
+    Ok(ChannelState::Open)
+  }
+}
+
+/// Whether the invoke accepts its channel messages in the response.
+pub(crate) fn streamed(request: &Request, payload: &InvokePayload) -> bool {
+  header(request, "Accept")
+    .map(|accept| accept.to_ascii_lowercase().contains(NDJSON))
+    .unwrap_or_default()
+    && payload.inner.as_object().map_or(false, |args| {
+      args
+        .values()
+        .any(|v| is_channel(v) || cancel::is_cancellation(v))
+    })
+}
+
+fn is_channel(value: &JsonValue) -> bool {
+  value.as_str().map_or(false, |v| v.starts_with(PREFIX))
+}
+
+/// The response of an invoke streaming its channel messages.
+pub(crate) struct ChannelStream {
+  /// Unset if the client was gone before the command ran.
+  writer: Option<Arc<BufferedWriter>>,
+}
+
+/// The invokes streaming their channel messages, by callback id.
+///
+/// Managed by the app, so [`Channel::send`] can reach them.
+#[derive(Default)]
+pub(crate) struct ChannelStreams {
+  pending: Mutex<HashMap<usize, ChannelStream>>,
+}
+
+impl ChannelStreams {
+  /// Starts the response of the invoke, to which its channel messages are sent.
+  ///
+  /// The channel arguments are renamed after the invoke, so their messages can't reach other clients.
+  pub(crate) fn start(
+    &self,
+    request: Request,
+    payload: &mut InvokePayload,
+    invoke_id: usize,
+    config: &Config,
+  ) {
+    if let Some(args) = payload.inner.as_object_mut() {
+      for value in args.values_mut().filter(|v| is_channel(v)) {
+        *value = format!("{}http-{}", PREFIX, invoke_id).into();
+      }
+    }
+
+    let mut r = Response::empty(200u16);
+    cors(&request, &mut r, &config.allowed_origins);
+    let mut headers = r.headers().to_vec();
+    headers.push(Header::from_bytes("Content-Type", NDJSON).unwrap());
+    let overflow = line(&json!({
+      "status": 503,
+      "code": ErrorCode::StreamOverflow.as_str(),
+      "error": "the client didn't keep up with the channel messages",
+    }));
+    // without a client, the command still runs for its side effects
+    let writer = ChunkedWriter::start(request, 200, &headers)
+      .ok()
+      .map(|writer| {
+        Arc::new(BufferedWriter::spawn(
+          writer,
+          config.stream_buffer,
+          config.backpressure,
+          Some(overflow),
+          // blank lines, skipped by the clients
+          Some(b"\n".to_vec()),
+        ))
+      });
+    self
+      .pending
+      .lock()
+      .unwrap()
+      .insert(invoke_id, ChannelStream { writer });
+  }
+
+  /// Sends a message to the stream of the channel, returning `None` if the channel isn't streamed.
+  fn send(&self, id: &str, message: &JsonValue) -> Option<ChannelState> {
+    let invoke_id = id.strip_prefix("http-")?.parse::<usize>().ok()?;
+    let writer = self.pending.lock().unwrap().get(&invoke_id)?.writer.clone();
+    // the sender may block, so the streams aren't kept locked
+    Some(match writer {
+      Some(writer) => writer.send(line(&json!({ "message": message }))),
+      None => ChannelState::Closed,
+    })
+  }
+
+  /// Whether the client of the streamed invoke is gone.
+  pub(crate) fn is_closed(&self, invoke_id: usize) -> bool {
+    match self.pending.lock().unwrap().get(&invoke_id) {
+      Some(stream) => stream.writer.as_ref().map_or(true, |w| w.is_closed()),
+      None => false,
+    }
+  }
+
+  /// Returns the stream of the invoke, if it has one.
+  pub(crate) fn take(&self, invoke_id: usize) -> Option<ChannelStream> {
+    self.pending.lock().unwrap().remove(&invoke_id)
+  }
+}
+
+impl ChannelStream {
+  /// Ends the stream with the command response.
+  pub(crate) fn finish(self, response: Result<JsonValue, JsonValue>, config: &Config) {
+    let last = match response {
+      Ok(value) => json!({ "status": 200, "result": value }),
+      Err(error) => json!({
+        "status": error_status(&error, config),
+        "code": ErrorCode::CommandError.as_str(),
+        "error": error,
+      }),
+    };
+    if let Some(writer) = self.writer {
+      writer.end(line(&last));
+    }
+  }
+}
+
+fn line(value: &JsonValue) -> Vec<u8> {
+  let mut line = serde_json::to_vec(value).unwrap();
+  line.push(b'\n');
+  line
+}
@@ -0,0 +1,149 @@
+// Copyright 2019-2021 Tauri Programme within The Commons Conservancy
+// SPDX-License-Identifier: Apache-2.0
+// SPDX-License-Identifier: MIT
+
+//! Response compression negotiated with the `Accept-Encoding` request header.
+
+use std::io::{Read, Write};
+
+use crate::body::LimitedReader;
+
+/// A supported content encoding.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Encoding {
+  Brotli,
+  Gzip,
+}
+
+impl Encoding {
+  pub(crate) fn name(self) -> &'static str {
+    match self {
+      Self::Brotli => "br",
+      Self::Gzip => "gzip",
+    }
+  }
+}
+
+/// Picks the preferred encoding supported by the client, favoring brotli on ties.
+pub(crate) fn negotiate(accept_encoding: &str) -> Option<Encoding> {
+  let mut best: Option<(Encoding, f32)> = None;
+  for item in accept_encoding.split(',') {
+    let mut pieces = item.split(';');
+    let name = pieces.next().unwrap_or_default().trim();
+    let quality = pieces
+      .find_map(|p| p.trim().strip_prefix("q="))
+      .and_then(|q| q.trim().parse::<f32>().ok())
+      .unwrap_or(1.0);
+    let encoding = match name.to_ascii_lowercase().as_str() {
+      "br" => Encoding::Brotli,
+      "gzip" | "x-gzip" => Encoding::Gzip,
+      _ => continue,
+    };
+    if quality <= 0.0 {
+      continue;
+    }
+    let better = match best {
+      Some((current, q)) => {
+        quality > q || (quality == q && encoding == Encoding::Brotli && current != encoding)
+      }
+      None => true,
+    };
+    if better {
+      best.replace((encoding, quality));
+    }
+  }
+  best.map(|(encoding, _)| encoding)
+}
+
+/// Compresses a buffered body.
+pub(crate) fn compress(encoding: Encoding, data: &[u8]) -> std::io::Result<Vec<u8>> {
+  match encoding {
+    Encoding::Brotli => {
+      let mut output = Vec::new();
+      {
+        let mut writer = brotli::CompressorWriter::new(&mut output, 4096, 5, 22);
+        writer.write_all(data)?;
+      }
+      Ok(output)
+    }
+    Encoding::Gzip => {
+      let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+      encoder.write_all(data)?;
+      encoder.finish()
+    }
+  }
+}
+
+/// Compresses a streamed body.
+pub(crate) fn compress_reader<R: Read + Send + 'static>(
+  encoding: Encoding,
+  reader: R,
+) -> Box<dyn Read + Send> {
+  match encoding {
+    Encoding::Brotli => Box::new(brotli::CompressorReader::new(reader, 4096, 5, 22)),
+    Encoding::Gzip => Box::new(flate2::read::GzEncoder::new(
+      reader,
+      flate2::Compression::default(),
+    )),
+  }
+}
+
+/// Whether request bodies with the given `Content-Encoding` can be decompressed.
+pub(crate) fn is_supported(content_encoding: &str) -> bool {
+  matches!(
+    content_encoding.trim().to_ascii_lowercase().as_str(),
+    "br" | "gzip" | "x-gzip" | "deflate"
+  )
+}
+
+/// Decompresses a request body encoded with the given `Content-Encoding`,
+/// reading at most `limit` decompressed bytes.
+///
+/// Returns `None` if the encoding is not supported.
+pub(crate) fn decompress_reader<'a>(
+  content_encoding: &str,
+  reader: Box<dyn Read + 'a>,
+  limit: u64,
+) -> Option<Box<dyn Read + 'a>> {
+  let decoder: Box<dyn Read + 'a> = match content_encoding.trim().to_ascii_lowercase().as_str() {
+    "br" => Box::new(brotli::Decompressor::new(reader, 4096)),
+    "gzip" | "x-gzip" => Box::new(flate2::read::MultiGzDecoder::new(reader)),
+    "deflate" => Box::new(flate2::read::ZlibDecoder::new(reader)),
+    _ => return None,
+  };
+  Some(Box::new(LimitedReader::new(decoder, limit)))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn negotiates_the_preferred_encoding() {
+    assert_eq!(negotiate("gzip"), Some(Encoding::Gzip));
+    assert_eq!(negotiate("gzip, deflate, br"), Some(Encoding::Brotli));
+    assert_eq!(negotiate("br;q=0.5, gzip;q=0.8"), Some(Encoding::Gzip));
+    assert_eq!(negotiate("X-GZIP ; q=0.3"), Some(Encoding::Gzip));
+  }
+
+  #[test]
+  fn ignores_refused_and_unknown_encodings() {
+    assert_eq!(negotiate(""), None);
+    assert_eq!(negotiate("identity, deflate"), None);
+    assert_eq!(negotiate("br;q=0, gzip;q=0.0"), None);
+    assert_eq!(negotiate("br;q=0, gzip"), Some(Encoding::Gzip));
+  }
+
+  #[test]
+  fn decompresses_the_compressed_bodies() {
+    let data = b"hello hello hello hello".repeat(16);
+    for encoding in [Encoding::Brotli, Encoding::Gzip] {
+      let compressed = compress(encoding, &data).unwrap();
+      let mut reader = decompress_reader(encoding.name(), Box::new(&compressed[..]), 1 << 20)
+        .expect("supported encoding");
+      let mut decompressed = Vec::new();
+      reader.read_to_end(&mut decompressed).unwrap();
+      assert_eq!(decompressed, data);
+    }
+  }
+}
@@ -0,0 +1,30 @@
+// Copyright 2019-2021 Tauri Programme within The Commons Conservancy
+// SPDX-License-Identifier: Apache-2.0
+// SPDX-License-Identifier: MIT
+
+//! The connection descriptor of `Invoke::connection_info`, to show the clients how to reach the server.
+
+use serde::Serialize;
+
+/// How to reach the invoke server, see `Invoke::connection_info`.
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+#[non_exhaustive]
+pub struct ConnectionInfo {
+  /// The base URL of the invokes, such as `http://localhost:8080`.
+  pub url: String,
+  /// The URL of the event WebSocket, if enabled with `Invoke::websocket_events`.
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub websocket_url: Option<String>,
+  /// The public URL of the `Invoke::tunnel`, once started.
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub public_url: Option<String>,
+}
+
+impl ConnectionInfo {
+  /// The descriptor as compact JSON, such as `{"url":"http://localhost:8080"}`,
+  /// to encode in a QR code scanned by the clients.
+  pub fn payload(&self) -> String {
+    serde_json::to_string(self).unwrap()
+  }
+}
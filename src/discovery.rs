@@ -0,0 +1,27 @@
+// Copyright 2019-2021 Tauri Programme within The Commons Conservancy
+// SPDX-License-Identifier: Apache-2.0
+// SPDX-License-Identifier: MIT
+
+//! The well-known bootstrap of `Invoke::discovery_ports`: `GET /.well-known/tauri-invoke`
+//! answers with the `ConnectionInfo` of the server, so the initialization script of a remotely
+//! hosted frontend finds the server by probing its candidate ports.
+
+use tiny_http::{Header, Method, Request, Response};
+
+use crate::{batch, cors, Config, ConnectionInfo, HttpError};
+
+pub(crate) const PATH: &str = "/.well-known/tauri-invoke";
+
+/// Answers `GET /.well-known/tauri-invoke` with how to reach the server.
+pub(crate) fn handle(request: Request, info: &ConnectionInfo, config: &Config) {
+  if !matches!(request.method(), Method::Get | Method::Head) {
+    let error = HttpError::method_not_allowed("GET, HEAD, OPTIONS");
+    return batch::respond_error(request, error, config);
+  }
+  let mut r = Response::from_data(info.payload())
+    .with_header(Header::from_bytes("Content-Type", "application/json").unwrap())
+    .with_header(Header::from_bytes("Cache-Control", "no-store").unwrap());
+  // only the allowed origins read where the server is
+  cors(&request, &mut r, &config.allowed_origins);
+  let _ = request.respond(r);
+}
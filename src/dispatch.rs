@@ -0,0 +1,316 @@
+// Copyright 2019-2021 Tauri Programme within The Commons Conservancy
+// SPDX-License-Identifier: Apache-2.0
+// SPDX-License-Identifier: MIT
+
+//! Dispatch of the invokes to their window, in arrival order with `Invoke::ordered_invokes`
+//! and up to `Invoke::concurrency_limit` at a time.
+//!
+//! Separate HTTP connections give no ordering, so the ordered invokes of a window are queued
+//! and dispatched one at a time, the next one once the previous one responded. Limited windows
+//! queue the invokes past their limit the same way.
+//!
+//! Each queued invoke is dispatched on its own thread: on the responder thread of the previous
+//! invoke, a synchronous command would respond while that response is still being sent.
+
+use std::{
+  collections::{HashMap, HashSet, VecDeque},
+  panic::{self, AssertUnwindSafe},
+  sync::{
+    atomic::{AtomicUsize, Ordering},
+    Arc, Mutex,
+  },
+};
+
+use serde_json::{Map, Value as JsonValue};
+use tauri::{api::ipc::CallbackFn, InvokePayload, Runtime, Window};
+
+use crate::{inspector::token_matches, trace, Config, ErrorCode, HttpError};
+
+type Task = Box<dyn FnOnce() + Send>;
+
+/// The command of the invokes checking an invoke key, of a plugin that doesn't exist.
+const PROBE_COMMAND: &str = "plugin:__tauri-invoke-http|authorize";
+
+#[derive(Default)]
+struct WindowQueue {
+  /// The callback ids of the dispatched invokes that didn't respond yet.
+  running: HashSet<usize>,
+  queued: VecDeque<(usize, Task)>,
+}
+
+/// The queued invokes, by window label.
+#[derive(Default)]
+pub(crate) struct Dispatcher {
+  windows: Mutex<HashMap<String, WindowQueue>>,
+  /// The invoke key of the app, learned from the first invoke a window accepted,
+  /// as Tauri 1 doesn't expose it.
+  invoke_key: Mutex<Option<String>>,
+  /// The callback ids of the invokes checking an invoke key, answered by their window.
+  probes: Mutex<HashSet<usize>>,
+  next_probe: AtomicUsize,
+}
+
+/// The maximum number of running invokes per window and of queued ones, if limited.
+///
+/// At least one invoke runs at a time, or the queued ones would never be dispatched.
+fn limits(config: &Config) -> Option<(usize, usize)> {
+  match (config.ordered_invokes, config.concurrency_limit) {
+    (true, Some((_, queue_depth))) => Some((1, queue_depth)),
+    (true, None) => Some((1, usize::MAX)),
+    (false, limit) => limit.map(|(max_running, queue_depth)| (max_running.max(1), queue_depth)),
+  }
+}
+
+/// Rejects the invokes without a `__TAURI_INVOKE_KEY__`, which their window would refuse.
+pub(crate) fn check_invoke_key(payload: &InvokePayload) -> Result<(), HttpError> {
+  match payload.invoke_key {
+    Some(_) => Ok(()),
+    None => Err(missing_invoke_key()),
+  }
+}
+
+fn missing_invoke_key() -> HttpError {
+  HttpError::new(
+    401,
+    ErrorCode::MissingInvokeKey,
+    "the invoke has no `__TAURI_INVOKE_KEY__`",
+  )
+}
+
+fn invalid_invoke_key() -> HttpError {
+  HttpError::new(
+    403,
+    ErrorCode::InvalidInvokeKey,
+    "the `__TAURI_INVOKE_KEY__` of the invoke is invalid",
+  )
+}
+
+/// Runs the invoke, returning why it will never respond if it failed.
+pub(crate) fn run<R: Runtime>(window: Window<R>, payload: InvokePayload) -> Result<(), HttpError> {
+  check_invoke_key(&payload)?;
+  trace::record_dispatch();
+  // synchronous commands run on the calling thread
+  match panic::catch_unwind(AssertUnwindSafe(|| window.on_message(payload))) {
+    Ok(Ok(())) => Ok(()),
+    Ok(Err(tauri::Error::InvokeKey)) => Err(invalid_invoke_key()),
+    Ok(Err(e)) => Err(HttpError::new(500, ErrorCode::InternalError, e.to_string())),
+    Err(_) => Err(HttpError::new(
+      500,
+      ErrorCode::InternalError,
+      "the command panicked",
+    )),
+  }
+}
+
+impl Dispatcher {
+  /// Whether the `__TAURI_INVOKE_KEY__` of the invoke is the one of the app, so its response
+  /// can come from the cache or from an identical invoke instead of its window.
+  ///
+  /// The key is unknown until a window accepted an invoke, and the invokes with another key
+  /// are refused.
+  pub(crate) fn verify_invoke_key(&self, payload: &InvokePayload) -> Result<bool, HttpError> {
+    check_invoke_key(payload)?;
+    let sent = payload.invoke_key.as_deref().unwrap_or_default();
+    match &*self.invoke_key.lock().unwrap() {
+      Some(expected) if token_matches(sent, expected) => Ok(true),
+      Some(_) => Err(invalid_invoke_key()),
+      None => Ok(false),
+    }
+  }
+
+  /// Checks the `__TAURI_INVOKE_KEY__` of a request that doesn't invoke a command, such as
+  /// an event subscription, like its window would check the key of an invoke.
+  ///
+  /// Until the key is learned, the window is sent an invoke of a plugin that doesn't exist,
+  /// which it refuses if the key is invalid and answers with an error otherwise.
+  pub(crate) fn authorize<R: Runtime>(
+    &self,
+    window: &Window<R>,
+    invoke_key: Option<&str>,
+  ) -> Result<(), HttpError> {
+    let sent = invoke_key.ok_or_else(missing_invoke_key)?;
+    if let Some(expected) = &*self.invoke_key.lock().unwrap() {
+      return if token_matches(sent, expected) {
+        Ok(())
+      } else {
+        Err(invalid_invoke_key())
+      };
+    }
+    let id = usize::MAX - self.next_probe.fetch_add(1, Ordering::Relaxed);
+    self.probes.lock().unwrap().insert(id);
+    let payload = InvokePayload {
+      cmd: PROBE_COMMAND.into(),
+      tauri_module: None,
+      invoke_key: Some(sent.into()),
+      callback: CallbackFn(id),
+      error: CallbackFn(id),
+      inner: JsonValue::Object(Map::new()),
+    };
+    // the window answers the probe before returning
+    let result = self.run(window.clone(), payload);
+    self.probes.lock().unwrap().remove(&id);
+    result
+  }
+
+  /// Whether the response is the one of an invoke checking an invoke key, which has no client.
+  pub(crate) fn is_probe(&self, id: usize) -> bool {
+    self.probes.lock().unwrap().contains(&id)
+  }
+
+  /// Runs the invoke, remembering its key once its window accepted it.
+  fn run<R: Runtime>(&self, window: Window<R>, payload: InvokePayload) -> Result<(), HttpError> {
+    let key = payload.invoke_key.clone();
+    let result = run(window, payload);
+    let refused = result.as_ref().err().map_or(false, |e| {
+      matches!(
+        e.code,
+        ErrorCode::MissingInvokeKey | ErrorCode::InvalidInvokeKey
+      )
+    });
+    if !refused {
+      let mut invoke_key = self.invoke_key.lock().unwrap();
+      if invoke_key.is_none() {
+        *invoke_key = key;
+      }
+    }
+    result
+  }
+
+  /// Whether an invoke of the window can be dispatched or queued.
+  pub(crate) fn admits(&self, label: &str, config: &Config) -> bool {
+    let (max_running, queue_depth) = match limits(config) {
+      Some(limits) => limits,
+      None => return true,
+    };
+    match self.windows.lock().unwrap().get(label) {
+      Some(queue) => queue.running.len() < max_running || queue.queued.len() < queue_depth,
+      None => true,
+    }
+  }
+
+  /// Dispatches the invoke, or queues it behind the running invokes of its window.
+  ///
+  /// `on_error` is called with the reason if the window refuses the invoke or its command panics,
+  /// as it then never responds.
+  pub(crate) fn dispatch<R: Runtime>(
+    self: &Arc<Self>,
+    window: Window<R>,
+    payload: InvokePayload,
+    config: &Config,
+    on_error: impl FnOnce(HttpError) + Send + 'static,
+  ) {
+    let max_running = match limits(config) {
+      Some((max_running, _)) => max_running,
+      None => {
+        if let Err(e) = self.run(window, payload) {
+          on_error(e);
+        }
+        return;
+      }
+    };
+
+    let id = payload.callback.0;
+    let label = window.label().to_string();
+    let dispatcher = self.clone();
+    let task_label = label.clone();
+    // queued tasks run on their own thread
+    let trace = trace::current();
+    let task: Task = Box::new(move || {
+      let _entered = trace.as_ref().map(|trace| trace.enter());
+      if let Err(e) = dispatcher.run(window, payload) {
+        on_error(e);
+        dispatcher.finish(&task_label, id);
+      }
+    });
+
+    let mut windows = self.windows.lock().unwrap();
+    let queue = windows.entry(label).or_default();
+    if queue.running.len() < max_running {
+      queue.running.insert(id);
+      drop(windows);
+      task();
+    } else {
+      queue.queued.push_back((id, task));
+    }
+  }
+
+  /// Dispatches the next queued invoke of the window once an invoke responded.
+  pub(crate) fn finish(&self, label: &str, id: usize) {
+    let mut windows = self.windows.lock().unwrap();
+    let queue = match windows.get_mut(label) {
+      Some(queue) => queue,
+      None => return,
+    };
+    // the invokes dispatched without limits aren't tracked
+    if !queue.running.remove(&id) {
+      return;
+    }
+    let next = queue.queued.pop_front();
+    match &next {
+      Some((next_id, _)) => {
+        queue.running.insert(*next_id);
+      }
+      None if queue.running.is_empty() => {
+        windows.remove(label);
+      }
+      None => {}
+    }
+    drop(windows);
+    if let Some((_, task)) = next {
+      std::thread::spawn(task);
+    }
+  }
+}
+
+/// Dispatches the next queued invoke of the window once dropped, so the responder
+/// sends the response of the finished invoke first.
+pub(crate) struct Finish<'a> {
+  pub(crate) dispatcher: &'a Dispatcher,
+  pub(crate) label: String,
+  pub(crate) id: usize,
+}
+
+impl Drop for Finish<'_> {
+  fn drop(&mut self) {
+    self.dispatcher.finish(&self.label, self.id);
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn config(ordered_invokes: bool, concurrency_limit: Option<(usize, usize)>) -> Config {
+    Config {
+      ordered_invokes,
+      concurrency_limit,
+      ..Default::default()
+    }
+  }
+
+  #[test]
+  fn limits_the_running_invokes() {
+    assert_eq!(limits(&config(false, None)), None);
+    assert_eq!(limits(&config(false, Some((4, 16)))), Some((4, 16)));
+    assert_eq!(limits(&config(true, None)), Some((1, usize::MAX)));
+    assert_eq!(limits(&config(true, Some((4, 16)))), Some((1, 16)));
+  }
+
+  #[test]
+  fn runs_one_invoke_with_a_zero_limit() {
+    assert_eq!(limits(&config(false, Some((0, 16)))), Some((1, 16)));
+    let dispatcher = Dispatcher::default();
+    let queue = |running: &[usize]| WindowQueue {
+      running: running.iter().copied().collect(),
+      queued: VecDeque::new(),
+    };
+    let mut windows = dispatcher.windows.lock().unwrap();
+    windows.insert("main".into(), queue(&[]));
+    windows.insert("busy".into(), queue(&[1]));
+    drop(windows);
+    let config = config(false, Some((0, 0)));
+    assert!(dispatcher.admits("main", &config));
+    assert!(!dispatcher.admits("busy", &config));
+    assert!(dispatcher.admits("settings", &config));
+  }
+}
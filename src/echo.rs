@@ -0,0 +1,58 @@
+// Copyright 2019-2021 Tauri Programme within The Commons Conservancy
+// SPDX-License-Identifier: Apache-2.0
+// SPDX-License-Identifier: MIT
+
+//! The debug echo of `Invoke::echo`: `/__echo/{window}/{cmd}` reads the invoke like
+//! `/{window}/{cmd}` but responds with what it read instead of dispatching it.
+//!
+//! The response is a `200 OK` JSON object, with the `window`, `cmd` and `args` of the invoke
+//! or the `status`, `code` and `error` the invoke would fail with.
+
+use serde_json::{json, Map, Value as JsonValue};
+use tauri::{AppHandle, Runtime};
+use tiny_http::{Header, Request, Response};
+
+use crate::{
+  batch::respond_error, cors, read_invoke, request_url, Config, HttpError, IncomingInvoke,
+};
+
+pub(crate) fn handle<R: Runtime>(app: &AppHandle<R>, mut request: Request, config: &Config) {
+  let mut url = match request_url(&request) {
+    Ok(url) => url,
+    Err(e) => return respond_error(request, e, config),
+  };
+  let path = url.path()["/__echo".len()..].to_string();
+  url.set_path(&path);
+
+  let headers: Map<String, JsonValue> = request
+    .headers()
+    .iter()
+    .map(|h| (h.field.as_str().to_string(), h.value.as_str().into()))
+    .collect();
+  let body = match read_invoke(app, &mut request, url, config) {
+    Ok(IncomingInvoke {
+      window, payload, ..
+    }) => json!({
+      "window": window.label(),
+      "cmd": payload.cmd,
+      "args": payload.inner,
+      "hasInvokeKey": payload.invoke_key.is_some(),
+      "headers": headers,
+    }),
+    Err(HttpError {
+      status,
+      code,
+      message,
+      ..
+    }) => json!({
+      "status": status,
+      "code": code.as_str(),
+      "error": message,
+      "headers": headers,
+    }),
+  };
+  let mut r = Response::from_data(serde_json::to_vec(&body).unwrap())
+    .with_header(Header::from_bytes("Content-Type", "application/json").unwrap());
+  cors(&request, &mut r, &config.allowed_origins);
+  let _ = request.respond(r);
+}
@@ -0,0 +1,92 @@
+// Copyright 2019-2021 Tauri Programme within The Commons Conservancy
+// SPDX-License-Identifier: Apache-2.0
+// SPDX-License-Identifier: MIT
+
+//! The `TAURI_INVOKE_HTTP_*` environment variables of `Invoke::env_overrides`.
+
+use std::{env, fmt, str::FromStr};
+
+pub(crate) const PORT: &str = "TAURI_INVOKE_HTTP_PORT";
+pub(crate) const ORIGINS: &str = "TAURI_INVOKE_HTTP_ORIGINS";
+pub(crate) const MAX_BODY_SIZE: &str = "TAURI_INVOKE_HTTP_MAX_BODY_SIZE";
+pub(crate) const RESPONSE_TIMEOUT_MS: &str = "TAURI_INVOKE_HTTP_RESPONSE_TIMEOUT_MS";
+pub(crate) const WORKER_THREADS: &str = "TAURI_INVOKE_HTTP_WORKER_THREADS";
+pub(crate) const BIND: &str = "TAURI_INVOKE_HTTP_BIND";
+
+/// An invalid `TAURI_INVOKE_HTTP_*` environment variable, see `Invoke::env_overrides`.
+#[derive(Debug, Clone)]
+pub struct EnvError {
+  /// The name of the variable.
+  pub var: &'static str,
+  /// Why its value is invalid.
+  pub reason: String,
+}
+
+impl fmt::Display for EnvError {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    write!(f, "invalid `{}`: {}", self.var, self.reason)
+  }
+}
+
+impl std::error::Error for EnvError {}
+
+/// The value of the variable, if it is set and not empty.
+pub(crate) fn var(name: &'static str) -> Result<Option<String>, EnvError> {
+  match env::var(name) {
+    Ok(value) if value.trim().is_empty() => Ok(None),
+    Ok(value) => Ok(Some(value.trim().to_string())),
+    Err(env::VarError::NotPresent) => Ok(None),
+    Err(env::VarError::NotUnicode(_)) => Err(EnvError {
+      var: name,
+      reason: "the value is not valid unicode".into(),
+    }),
+  }
+}
+
+/// The parsed value of the variable, if it is set and not empty.
+pub(crate) fn parse<T: FromStr>(name: &'static str) -> Result<Option<T>, EnvError>
+where
+  T::Err: fmt::Display,
+{
+  var(name)?
+    .map(|value| {
+      value.parse().map_err(|e| EnvError {
+        var: name,
+        reason: format!("`{}`: {}", value, e),
+      })
+    })
+    .transpose()
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn reads_set_variables() {
+    const NAME: &str = "TAURI_INVOKE_HTTP_TEST_SET";
+    env::set_var(NAME, " 8080 ");
+    assert_eq!(var(NAME).unwrap().as_deref(), Some("8080"));
+    assert_eq!(parse::<u16>(NAME).unwrap(), Some(8080));
+  }
+
+  #[test]
+  fn ignores_unset_and_empty_variables() {
+    const NAME: &str = "TAURI_INVOKE_HTTP_TEST_EMPTY";
+    assert_eq!(parse::<u16>(NAME).unwrap(), None);
+    env::set_var(NAME, "  ");
+    assert_eq!(var(NAME).unwrap(), None);
+    assert_eq!(parse::<u16>(NAME).unwrap(), None);
+  }
+
+  #[test]
+  fn reports_invalid_values() {
+    const NAME: &str = "TAURI_INVOKE_HTTP_TEST_INVALID";
+    env::set_var(NAME, "99999");
+    let error = parse::<u16>(NAME).unwrap_err();
+    assert_eq!(error.var, NAME);
+    assert!(error
+      .to_string()
+      .starts_with("invalid `TAURI_INVOKE_HTTP_TEST_INVALID`: `99999`"));
+  }
+}
@@ -0,0 +1,315 @@
+// Copyright 2019-2021 Tauri Programme within The Commons Conservancy
+// SPDX-License-Identifier: Apache-2.0
+// SPDX-License-Identifier: MIT
+
+//! Rendering of failure responses.
+
+use serde_json::{json, Value as JsonValue};
+use tiny_http::{Header, Response, StatusCode};
+
+use crate::buffer::{self, PooledBody};
+
+/// The body format of failure responses.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorFormat {
+  /// Transport errors are plain text and command errors are their serialized JSON value.
+  Plain,
+  /// Both transport and command errors are `application/json` objects with the response `status`,
+  /// the error `code` and the `error` message or command error value.
+  Json,
+  /// Both transport and command errors are [RFC 7807](https://www.rfc-editor.org/rfc/rfc7807)
+  /// `application/problem+json` objects with the `type`, `title`, `status` and `detail` fields
+  /// and the `code` extension member.
+  ProblemJson,
+}
+
+impl Default for ErrorFormat {
+  fn default() -> Self {
+    Self::Plain
+  }
+}
+
+/// A stable identifier of the cause of a failure response,
+/// sent in the `Tauri-Error-Code` header and in the JSON error bodies.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum ErrorCode {
+  /// The request URL could not be parsed.
+  InvalidUrl,
+  /// The target window does not exist.
+  UnknownWindow,
+  /// The command does not exist or can't be invoked with this method.
+  UnknownCommand,
+  /// The command arguments could not be read from the URL.
+  InvalidArgs,
+  /// The request body could not be read or parsed.
+  InvalidBody,
+  /// The request body exceeds the configured size limit.
+  BodyTooLarge,
+  /// The request `Content-Encoding` is not supported.
+  UnsupportedEncoding,
+  /// The request `Content-Type` is not supported.
+  UnsupportedMediaType,
+  /// The request method is not supported by the route.
+  MethodNotAllowed,
+  /// The requested range of a raw response can't be satisfied.
+  RangeNotSatisfiable,
+  /// The file of a raw response could not be opened.
+  ResponseFileError,
+  /// Resumable uploads are not enabled.
+  UploadsDisabled,
+  /// The resumable upload does not exist.
+  UnknownUpload,
+  /// A resumable upload header is missing or invalid.
+  InvalidUploadHeader,
+  /// The resumable upload offset does not match the received bytes.
+  UploadOffsetMismatch,
+  /// Another `PATCH` request is writing to the resumable upload.
+  UploadInProgress,
+  /// The `Tauri-Callback-Url` of the request is invalid or not allowed.
+  InvalidCallbackUrl,
+  /// The job does not exist.
+  UnknownJob,
+  /// A request with the same `Idempotency-Key` is still running.
+  IdempotencyKeyInUse,
+  /// The `Idempotency-Key` was used by a request with a different command or arguments.
+  IdempotencyKeyReused,
+  /// The `Origin` of the request is not allowed.
+  OriginNotAllowed,
+  /// The event is not listed in `Invoke::subscribable_events` or `Invoke::emittable_events`.
+  EventNotAllowed,
+  /// The client didn't keep up with a stream closed by `Backpressure::Close`.
+  StreamOverflow,
+  /// The path has segments past the window and command, in strict path mode.
+  UnexpectedPathSegment,
+  /// The `Expect` request header is not `100-continue`.
+  ExpectationFailed,
+  /// No cancellable invoke is running with the `Tauri-Invoke-Id`.
+  UnknownInvoke,
+  /// The window has too many running and queued invokes, see `Invoke::concurrency_limit`.
+  TooManyInvokes,
+  /// The `Origin` request header is neither `null` nor a `scheme://host[:port]` origin.
+  InvalidOrigin,
+  /// The invoke has no `__TAURI_INVOKE_KEY__`.
+  MissingInvokeKey,
+  /// The `__TAURI_INVOKE_KEY__` of the invoke doesn't match the key of the app.
+  InvalidInvokeKey,
+  /// The invoke body has no `callback` or `error` field.
+  MissingCallback,
+  /// The command didn't respond within `Invoke::response_timeout`.
+  ResponseTimeout,
+  /// The `Authorization` bearer token of a protected route is missing or invalid.
+  InvalidToken,
+  /// The server failed to process the request.
+  InternalError,
+  /// The command returned an error.
+  CommandError,
+}
+
+impl ErrorCode {
+  /// The code as sent to the client.
+  pub fn as_str(self) -> &'static str {
+    match self {
+      Self::InvalidUrl => "INVALID_URL",
+      Self::UnknownWindow => "UNKNOWN_WINDOW",
+      Self::UnknownCommand => "UNKNOWN_COMMAND",
+      Self::InvalidArgs => "INVALID_ARGS",
+      Self::InvalidBody => "INVALID_BODY",
+      Self::BodyTooLarge => "BODY_TOO_LARGE",
+      Self::UnsupportedEncoding => "UNSUPPORTED_ENCODING",
+      Self::UnsupportedMediaType => "UNSUPPORTED_MEDIA_TYPE",
+      Self::MethodNotAllowed => "METHOD_NOT_ALLOWED",
+      Self::RangeNotSatisfiable => "RANGE_NOT_SATISFIABLE",
+      Self::ResponseFileError => "RESPONSE_FILE_ERROR",
+      Self::UploadsDisabled => "UPLOADS_DISABLED",
+      Self::UnknownUpload => "UNKNOWN_UPLOAD",
+      Self::InvalidUploadHeader => "INVALID_UPLOAD_HEADER",
+      Self::UploadOffsetMismatch => "UPLOAD_OFFSET_MISMATCH",
+      Self::UploadInProgress => "UPLOAD_IN_PROGRESS",
+      Self::InvalidCallbackUrl => "INVALID_CALLBACK_URL",
+      Self::UnknownJob => "UNKNOWN_JOB",
+      Self::IdempotencyKeyInUse => "IDEMPOTENCY_KEY_IN_USE",
+      Self::IdempotencyKeyReused => "IDEMPOTENCY_KEY_REUSED",
+      Self::OriginNotAllowed => "ORIGIN_NOT_ALLOWED",
+      Self::EventNotAllowed => "EVENT_NOT_ALLOWED",
+      Self::StreamOverflow => "STREAM_OVERFLOW",
+      Self::UnexpectedPathSegment => "UNEXPECTED_PATH_SEGMENT",
+      Self::ExpectationFailed => "EXPECTATION_FAILED",
+      Self::UnknownInvoke => "UNKNOWN_INVOKE",
+      Self::TooManyInvokes => "TOO_MANY_INVOKES",
+      Self::InvalidOrigin => "INVALID_ORIGIN",
+      Self::MissingInvokeKey => "MISSING_INVOKE_KEY",
+      Self::InvalidInvokeKey => "INVALID_INVOKE_KEY",
+      Self::MissingCallback => "MISSING_CALLBACK",
+      Self::ResponseTimeout => "RESPONSE_TIMEOUT",
+      Self::InvalidToken => "INVALID_TOKEN",
+      Self::InternalError => "INTERNAL_ERROR",
+      Self::CommandError => "COMMAND_ERROR",
+    }
+  }
+}
+
+/// A failure that happened before the command could respond.
+#[derive(Debug, Clone)]
+pub(crate) struct HttpError {
+  pub(crate) status: u16,
+  pub(crate) code: ErrorCode,
+  pub(crate) message: String,
+  /// The methods of the route, sent in the `Allow` header of `405` errors.
+  pub(crate) allow: Option<&'static str>,
+}
+
+impl HttpError {
+  pub(crate) fn new(status: u16, code: ErrorCode, message: impl Into<String>) -> Self {
+    Self {
+      status,
+      code,
+      message: message.into(),
+      allow: None,
+    }
+  }
+
+  /// A `405 Method Not Allowed` error for a route accepting the given methods.
+  pub(crate) fn method_not_allowed(allow: &'static str) -> Self {
+    Self {
+      allow: Some(allow),
+      ..Self::new(405, ErrorCode::MethodNotAllowed, "method not allowed")
+    }
+  }
+}
+
+fn title(status: u16) -> &'static str {
+  StatusCode(status).default_reason_phrase()
+}
+
+fn problem_response(problem: JsonValue) -> Response<PooledBody> {
+  let status = problem["status"].as_u64().unwrap_or(500) as u16;
+  buffer::response(json_body(&problem))
+    .with_status_code(status)
+    .with_header(Header::from_bytes("Content-Type", "application/problem+json").unwrap())
+}
+
+fn json_body(value: &JsonValue) -> Vec<u8> {
+  let mut body = buffer::take();
+  serde_json::to_writer(&mut body, value).unwrap();
+  body
+}
+
+/// The headers carrying the error code, readable by cross origin clients.
+pub(crate) fn code_headers(code: ErrorCode) -> [Header; 2] {
+  [
+    Header::from_bytes("Tauri-Error-Code", code.as_str()).unwrap(),
+    Header::from_bytes("Access-Control-Expose-Headers", "Tauri-Error-Code").unwrap(),
+  ]
+}
+
+/// Renders a transport error with the given format.
+pub(crate) fn transport_error(error: &HttpError, format: ErrorFormat) -> Response<PooledBody> {
+  let status = error.status;
+  let code = error.code.as_str();
+  let response = match format {
+    ErrorFormat::Plain => {
+      let mut body = buffer::take();
+      body.extend_from_slice(error.message.as_bytes());
+      buffer::response(body)
+        .with_status_code(status)
+        .with_header(Header::from_bytes("Content-Type", "text/plain; charset=UTF-8").unwrap())
+    }
+    ErrorFormat::Json => buffer::response(json_body(
+      &json!({ "status": status, "code": code, "error": error.message }),
+    ))
+    .with_status_code(status)
+    .with_header(Header::from_bytes("Content-Type", "application/json").unwrap()),
+    ErrorFormat::ProblemJson => problem_response(json!({
+      "type": "about:blank",
+      "title": title(status),
+      "status": status,
+      "detail": error.message,
+      "code": code,
+    })),
+  };
+  crate::trace::record_response(status, response.data_length().map(|len| len as u64));
+  let [code, expose] = code_headers(error.code);
+  match error.allow {
+    Some(allow) => response
+      .with_header(code)
+      .with_header(Header::from_bytes("Allow", allow).unwrap())
+      .with_header(
+        Header::from_bytes("Access-Control-Expose-Headers", "Tauri-Error-Code, Allow").unwrap(),
+      ),
+    None => response.with_header(code).with_header(expose),
+  }
+}
+
+/// Wraps a command error in the JSON error envelope.
+pub(crate) fn command_envelope(error: JsonValue, status: u16) -> JsonValue {
+  json!({ "status": status, "code": ErrorCode::CommandError.as_str(), "error": error })
+}
+
+/// Converts a command error into a problem details object.
+///
+/// Object errors are extended with the missing problem fields, other values become the `detail`.
+pub(crate) fn command_problem(error: JsonValue, status: u16) -> JsonValue {
+  match error {
+    JsonValue::Object(mut problem) => {
+      problem
+        .entry("type")
+        .or_insert_with(|| "about:blank".into());
+      problem
+        .entry("title")
+        .or_insert_with(|| title(status).into());
+      problem
+        .entry("code")
+        .or_insert_with(|| ErrorCode::CommandError.as_str().into());
+      problem.insert("status".into(), status.into());
+      JsonValue::Object(problem)
+    }
+    detail => json!({
+      "type": "about:blank",
+      "title": title(status),
+      "status": status,
+      "detail": detail,
+      "code": ErrorCode::CommandError.as_str(),
+    }),
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn wraps_values_in_problems() {
+    assert_eq!(
+      command_problem("not found".into(), 404),
+      json!({
+        "type": "about:blank",
+        "title": "Not Found",
+        "status": 404,
+        "detail": "not found",
+        "code": "COMMAND_ERROR",
+      })
+    );
+  }
+
+  #[test]
+  fn completes_object_problems() {
+    let error =
+      json!({ "type": "https://example.com/out-of-stock", "detail": "none left", "status": 200 });
+    assert_eq!(
+      command_problem(error, 409),
+      json!({
+        "type": "https://example.com/out-of-stock",
+        "title": "Conflict",
+        "status": 409,
+        "detail": "none left",
+        "code": "COMMAND_ERROR",
+      })
+    );
+    let error = json!({ "title": "Out of stock", "code": "OUT_OF_STOCK" });
+    let problem = command_problem(error, 400);
+    assert_eq!(problem["title"], "Out of stock");
+    assert_eq!(problem["code"], "OUT_OF_STOCK");
+    assert_eq!(problem["type"], "about:blank");
+  }
+}
@@ -0,0 +1,581 @@
+// Copyright 2019-2021 Tauri Programme within The Commons Conservancy
+// SPDX-License-Identifier: Apache-2.0
+// SPDX-License-Identifier: MIT
+
+//! Tauri events forwarded to HTTP clients as [Server-Sent Events](https://html.spec.whatwg.org/multipage/server-sent-events.html)
+//! on `GET /__events?event={event}&window={window}`, or batched on `GET /__events/poll` for clients
+//! that can't stream, and emitted by them on `POST /__events/{event}`.
+//!
+//! The events are those of `Invoke::subscribable_events` received by `app.listen_global`, or by
+//! `window.listen` when a window is given: events emitted by the webviews and events triggered
+//! on the Rust side. The requests are checked like invokes, with their `__TAURI_INVOKE_KEY__`.
+//! The [WebSocket bridge](crate::websocket) is served from the same hub.
+
+use std::{
+  collections::{HashSet, VecDeque},
+  io::Read,
+  net::{Shutdown, TcpStream},
+  sync::{
+    atomic::{AtomicUsize, Ordering},
+    Arc, Condvar, Mutex,
+  },
+  time::{Duration, Instant},
+};
+
+use serde_json::{json, Value as JsonValue};
+use tauri::{AppHandle, Manager, Runtime, Url};
+use tiny_http::{Header, Method, Request, Response};
+
+use crate::{
+  batch::respond_error,
+  body_error, body_reader, cors,
+  dispatch::Dispatcher,
+  origin_allowed, request_url, respond_value,
+  stream::{BufferedWriter, ChunkedWriter},
+  Backpressure, ChannelState, Config, ErrorCode, HttpError,
+};
+
+/// The events of a listener: the window it is registered on, if any, and the event name.
+pub(crate) type Scope = (Option<String>, String);
+
+/// The default number of recent events kept for the long-polling and reconnecting clients.
+pub(crate) const DEFAULT_BUFFER_CAPACITY: usize = 1024;
+/// The default time recent events are kept for.
+pub(crate) const DEFAULT_RETENTION: Duration = Duration::from_secs(5 * 60);
+/// The time a long-polling request waits for an event.
+const POLL_TIMEOUT: Duration = Duration::from_secs(25);
+
+struct QueuedEvent {
+  id: u64,
+  scope: Scope,
+  data: JsonValue,
+  at: Instant,
+}
+
+/// The recent events, delivered to the long-polling clients and replayed to the reconnecting ones.
+#[derive(Default)]
+struct Queue {
+  events: VecDeque<QueuedEvent>,
+  next_id: u64,
+}
+
+/// The connection of a streaming client.
+pub(crate) enum Sink {
+  Sse(BufferedWriter),
+  /// The messages of a WebSocket, buffered like the Server-Sent Events,
+  /// and its connection, shut down once the messages are dropped.
+  WebSocket(BufferedWriter, TcpStream),
+}
+
+struct Subscriber {
+  id: usize,
+  sink: Sink,
+  scopes: HashSet<Scope>,
+}
+
+/// The backpressure policy of the streaming clients, which never block: the events are sent to them
+/// with the hub locked, so `Backpressure::Block` drops the oldest events instead.
+pub(crate) fn sink_backpressure(config: &Config) -> Backpressure {
+  match config.backpressure {
+    Backpressure::Block => Backpressure::DropOldest,
+    policy => policy,
+  }
+}
+
+/// Formats a Server-Sent Event, with the JSON payload on a single line.
+fn sse_message(id: u64, event: &str, data: &JsonValue) -> String {
+  format!("id: {}\nevent: {}\ndata: {}\n\n", id, event, data)
+}
+
+/// The events the clients of an origin may subscribe to and emit, as names or `prefix*` patterns.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct EventRule {
+  pub(crate) subscribe: Vec<String>,
+  pub(crate) emit: Vec<String>,
+}
+
+fn matches(patterns: &[String], event: &str) -> bool {
+  patterns.iter().any(|p| match p.strip_suffix('*') {
+    Some(prefix) => event.starts_with(prefix),
+    None => p == event,
+  })
+}
+
+/// Whether the rule of the origin, falling back to the `*` rule, allows the event.
+///
+/// Without rules, every event is allowed.
+fn allowed(
+  config: &Config,
+  origin: Option<&str>,
+  event: &str,
+  patterns: impl Fn(&EventRule) -> &[String],
+) -> bool {
+  if config.event_rules.is_empty() {
+    return true;
+  }
+  origin
+    .and_then(|o| config.event_rules.get(o))
+    .or_else(|| config.event_rules.get("*"))
+    .map_or(false, |rule| matches(patterns(rule), event))
+}
+
+/// Whether the clients of the origin may subscribe to the event.
+pub(crate) fn can_subscribe(config: &Config, origin: Option<&str>, event: &str) -> bool {
+  config.subscribable_events.contains(event)
+    && allowed(config, origin, event, |rule| &rule.subscribe)
+}
+
+/// Checks the `__TAURI_INVOKE_KEY__` of a request with its window, or with any window
+/// for the global events, as the key is the same for all of them.
+pub(crate) fn authorize<R: Runtime>(
+  app: &AppHandle<R>,
+  invoke_key: Option<&str>,
+  window: Option<&str>,
+  dispatcher: &Dispatcher,
+) -> Result<(), HttpError> {
+  let window = match window {
+    Some(label) => app.get_window(label),
+    None => app.windows().into_values().next(),
+  };
+  match window {
+    Some(window) => dispatcher.authorize(&window, invoke_key),
+    None => Err(HttpError::new(
+      404,
+      ErrorCode::UnknownWindow,
+      "no window to check the invoke key with",
+    )),
+  }
+}
+
+/// Whether the clients of the origin may emit the event.
+fn can_emit(config: &Config, origin: Option<&str>, event: &str) -> bool {
+  config.emittable_events.contains(event) && allowed(config, origin, event, |rule| &rule.emit)
+}
+
+/// The error of a subscription or emission denied to the client.
+pub(crate) fn not_allowed(event: &str) -> HttpError {
+  HttpError::new(
+    403,
+    ErrorCode::EventNotAllowed,
+    format!("event `{}` not allowed", event),
+  )
+}
+
+/// Whether Tauri accepts the event name, as listening to or emitting invalid events panics.
+pub(crate) fn valid_event_name(event: &str) -> bool {
+  !event.is_empty()
+    && event
+      .chars()
+      .all(|c| c.is_alphanumeric() || c == '-' || c == '/' || c == ':' || c == '_')
+}
+
+/// The event listeners registered for HTTP clients and the connected clients.
+#[derive(Default)]
+pub(crate) struct EventHub {
+  listening: Mutex<HashSet<Scope>>,
+  subscribers: Mutex<Vec<Subscriber>>,
+  next_subscriber: AtomicUsize,
+  queue: Mutex<Queue>,
+  /// Wakes the long-polling requests when an event is queued.
+  queued: Condvar,
+}
+
+impl EventHub {
+  /// Registers the listener of a scope, once.
+  pub(crate) fn listen<R: Runtime>(
+    self: &Arc<Self>,
+    app: &AppHandle<R>,
+    scope: &Scope,
+    config: &Config,
+  ) {
+    if !self.listening.lock().unwrap().insert(scope.clone()) {
+      return;
+    }
+    let hub = self.clone();
+    let forwarded = scope.clone();
+    let (capacity, retention) = (config.event_buffer_capacity, config.event_retention);
+    let handler =
+      move |event: tauri::Event| hub.publish(&forwarded, event.payload(), capacity, retention);
+    match &scope.0 {
+      Some(label) => {
+        if let Some(window) = app.get_window(label) {
+          window.listen(scope.1.clone(), handler);
+        }
+      }
+      None => {
+        app.listen_global(scope.1.clone(), handler);
+      }
+    }
+  }
+
+  /// Queues an event and sends it to the streaming clients subscribed to its scope,
+  /// dropping the disconnected ones.
+  fn publish(&self, scope: &Scope, payload: Option<&str>, capacity: usize, retention: Duration) {
+    let data = payload
+      .and_then(|p| serde_json::from_str::<JsonValue>(p).ok())
+      .unwrap_or_default();
+    // the queue stays locked while sending, so the replayed events of a new client don't overlap
+    let mut queue = self.queue.lock().unwrap();
+    let id = queue.next_id;
+    let message = sse_message(id, &scope.1, &data);
+    let frame = json!({
+      "type": "event",
+      "id": id,
+      "event": scope.1,
+      "windowLabel": scope.0,
+      "payload": data,
+    })
+    .to_string();
+    queue.next_id += 1;
+    let now = Instant::now();
+    queue.events.push_back(QueuedEvent {
+      id,
+      scope: scope.clone(),
+      data,
+      at: now,
+    });
+    while queue.events.len() > capacity
+      || queue
+        .events
+        .front()
+        .map_or(false, |e| now.duration_since(e.at) >= retention)
+    {
+      queue.events.pop_front();
+    }
+    self.queued.notify_all();
+
+    let mut subscribers = self.subscribers.lock().unwrap();
+    let mut i = 0;
+    while i < subscribers.len() {
+      let subscriber = &mut subscribers[i];
+      let sent = !subscriber.scopes.contains(scope)
+        || match &mut subscriber.sink {
+          Sink::Sse(writer) => writer.send(message.clone().into_bytes()) != ChannelState::Closed,
+          Sink::WebSocket(writer, stream) => {
+            let open = writer.send(frame.clone().into_bytes()) != ChannelState::Closed;
+            if !open {
+              // the connection thread stops reading, and removes the subscriber too
+              let _ = stream.shutdown(Shutdown::Both);
+            }
+            open
+          }
+        };
+      if !sent {
+        subscribers.swap_remove(i);
+      } else {
+        i += 1;
+      }
+    }
+    drop(queue);
+  }
+
+  /// Adds a streaming client, returning its id.
+  pub(crate) fn add_subscriber(&self, sink: Sink, scopes: HashSet<Scope>) -> usize {
+    let id = self.next_subscriber.fetch_add(1, Ordering::Relaxed);
+    self
+      .subscribers
+      .lock()
+      .unwrap()
+      .push(Subscriber { id, sink, scopes });
+    id
+  }
+
+  /// Changes the scopes of a streaming client.
+  pub(crate) fn update_scopes(&self, id: usize, update: impl FnOnce(&mut HashSet<Scope>)) {
+    if let Some(subscriber) = self
+      .subscribers
+      .lock()
+      .unwrap()
+      .iter_mut()
+      .find(|s| s.id == id)
+    {
+      update(&mut subscriber.scopes);
+    }
+  }
+
+  /// Removes a streaming client.
+  pub(crate) fn remove_subscriber(&self, id: usize) {
+    self.subscribers.lock().unwrap().retain(|s| s.id != id);
+  }
+
+  /// The number of streaming clients, including the disconnected ones not dropped yet.
+  pub(crate) fn subscriber_count(&self) -> usize {
+    self.subscribers.lock().unwrap().len()
+  }
+}
+
+/// Handles a request to the `/__events` routes.
+pub(crate) fn handle<R: Runtime>(
+  app: &AppHandle<R>,
+  request: Request,
+  config: &Arc<Config>,
+  hub: &Arc<EventHub>,
+  dispatcher: &Dispatcher,
+) {
+  if !origin_allowed(crate::header(&request, "Origin"), config) {
+    let error = HttpError::new(403, ErrorCode::OriginNotAllowed, "origin not allowed");
+    return respond_error(request, error, config);
+  }
+  let url = match request_url(&request) {
+    Ok(url) => url,
+    Err(e) => return respond_error(request, e, config),
+  };
+  let window = url
+    .query_pairs()
+    .find(|(key, _)| key == "window")
+    .map(|(_, value)| value.into_owned());
+  if let Some(label) = &window {
+    if app.get_window(label).is_none() {
+      let error = HttpError::new(
+        404,
+        ErrorCode::UnknownWindow,
+        format!("window `{}` not found", label),
+      );
+      return respond_error(request, error, config);
+    }
+  }
+  // the subscriptions and emissions are checked like invokes
+  if matches!(request.method(), Method::Get | Method::Post) {
+    let invoke_key = url
+      .query_pairs()
+      .find(|(key, _)| key == "__TAURI_INVOKE_KEY__")
+      .map(|(_, value)| value.into_owned());
+    if let Err(e) = authorize(app, invoke_key.as_deref(), window.as_deref(), dispatcher) {
+      return respond_error(request, e, config);
+    }
+  }
+
+  match (request.method(), url.path().strip_prefix("/__events/")) {
+    (Method::Head, None) => respond_head(request, "text/event-stream", config),
+    (Method::Head, Some("poll")) => respond_head(request, "application/json", config),
+    (Method::Get, None) => subscribe(app, request, &url, window, config, hub),
+    (Method::Get, Some("poll")) => poll(app, request, &url, window, config, hub),
+    (Method::Post, Some(event)) => {
+      let event = event.to_string();
+      emit(app, request, &event, window, config)
+    }
+    _ => respond_error(
+      request,
+      HttpError::method_not_allowed("GET, HEAD, POST, OPTIONS"),
+      config,
+    ),
+  }
+}
+
+/// Reads the scopes of the `event` query parameters and registers their listeners.
+fn listen<R: Runtime>(
+  app: &AppHandle<R>,
+  url: &Url,
+  window: Option<String>,
+  origin: Option<&str>,
+  config: &Config,
+  hub: &Arc<EventHub>,
+) -> Result<HashSet<Scope>, HttpError> {
+  let events: Vec<String> = url
+    .query_pairs()
+    .filter(|(key, _)| key == "event")
+    .map(|(_, value)| value.into_owned())
+    .collect();
+  if events.is_empty() {
+    return Err(HttpError::new(
+      400,
+      ErrorCode::InvalidArgs,
+      "missing `event` query parameter",
+    ));
+  }
+  if let Some(event) = events.iter().find(|e| !valid_event_name(e)) {
+    return Err(HttpError::new(
+      400,
+      ErrorCode::InvalidArgs,
+      format!("invalid event name `{}`", event),
+    ));
+  }
+  if let Some(event) = events.iter().find(|e| !can_subscribe(config, origin, e)) {
+    return Err(not_allowed(event));
+  }
+  let scopes: HashSet<Scope> = events
+    .into_iter()
+    .map(|event| (window.clone(), event))
+    .collect();
+  for scope in &scopes {
+    hub.listen(app, scope, config);
+  }
+  Ok(scopes)
+}
+
+/// Opens an event stream for the request.
+fn subscribe<R: Runtime>(
+  app: &AppHandle<R>,
+  request: Request,
+  url: &Url,
+  window: Option<String>,
+  config: &Config,
+  hub: &Arc<EventHub>,
+) {
+  let origin = crate::header(&request, "Origin");
+  let scopes = match listen(app, url, window, origin, config, hub) {
+    Ok(scopes) => scopes,
+    Err(e) => return respond_error(request, e, config),
+  };
+
+  let mut r = Response::empty(200u16);
+  cors(&request, &mut r, &config.allowed_origins);
+  let mut headers = r.headers().to_vec();
+  headers.push(Header::from_bytes("Content-Type", "text/event-stream").unwrap());
+  headers.push(Header::from_bytes("Cache-Control", "no-cache").unwrap());
+  // `EventSource` sends the id of the last received event when reconnecting
+  let last_event_id =
+    crate::header(&request, "Last-Event-ID").and_then(|id| id.parse::<u64>().ok());
+  let mut writer = match ChunkedWriter::start(request, 200, &headers) {
+    Ok(writer) => writer,
+    Err(_) => return,
+  };
+  let queue = hub.queue.lock().unwrap();
+  if let Some(last_event_id) = last_event_id {
+    for event in queue.events.iter().filter(|e| {
+      e.id > last_event_id && e.at.elapsed() < config.event_retention && scopes.contains(&e.scope)
+    }) {
+      if writer
+        .send(sse_message(event.id, &event.scope.1, &event.data).as_bytes())
+        .is_err()
+      {
+        return;
+      }
+    }
+  }
+  let writer = BufferedWriter::spawn(
+    writer,
+    config.stream_buffer,
+    sink_backpressure(config),
+    None,
+    None,
+  );
+  hub.add_subscriber(Sink::Sse(writer), scopes);
+  drop(queue);
+}
+
+/// Answers a `HEAD` request with the headers of the route, without subscribing.
+fn respond_head(request: Request, content_type: &str, config: &Config) {
+  let mut r =
+    Response::empty(200u16).with_header(Header::from_bytes("Content-Type", content_type).unwrap());
+  cors(&request, &mut r, &config.allowed_origins);
+  let _ = request.respond(r);
+}
+
+/// Answers the request with the events queued since its `cursor`,
+/// waiting for one if there are none.
+///
+/// Without a cursor, only the events queued after the request are returned.
+fn poll<R: Runtime>(
+  app: &AppHandle<R>,
+  request: Request,
+  url: &Url,
+  window: Option<String>,
+  config: &Arc<Config>,
+  hub: &Arc<EventHub>,
+) {
+  let cursor = match url.query_pairs().find(|(key, _)| key == "cursor") {
+    Some((_, cursor)) => match cursor.parse::<u64>() {
+      Ok(cursor) => Some(cursor),
+      Err(_) => {
+        let error = HttpError::new(
+          400,
+          ErrorCode::InvalidArgs,
+          "invalid `cursor` query parameter",
+        );
+        return respond_error(request, error, config);
+      }
+    },
+    None => None,
+  };
+  let origin = crate::header(&request, "Origin");
+  let scopes = match listen(app, url, window, origin, config, hub) {
+    Ok(scopes) => scopes,
+    Err(e) => return respond_error(request, e, config),
+  };
+
+  let hub = hub.clone();
+  let config = config.clone();
+  std::thread::spawn(move || {
+    let deadline = Instant::now() + POLL_TIMEOUT;
+    let mut queue = hub.queue.lock().unwrap();
+    let cursor = cursor.unwrap_or(queue.next_id);
+    let events = loop {
+      let events: Vec<JsonValue> = queue
+        .events
+        .iter()
+        .filter(|e| {
+          e.id >= cursor && e.at.elapsed() < config.event_retention && scopes.contains(&e.scope)
+        })
+        .map(|e| json!({ "id": e.id, "event": e.scope.1, "payload": e.data }))
+        .collect();
+      let now = Instant::now();
+      if !events.is_empty() || now >= deadline {
+        break events;
+      }
+      queue = hub.queued.wait_timeout(queue, deadline - now).unwrap().0;
+    };
+    let body = json!({ "cursor": queue.next_id, "events": events });
+    drop(queue);
+    respond_value(request, "", Ok(body), Vec::new(), &config);
+  });
+}
+
+/// Emits the event with the JSON payload of the request body.
+fn emit<R: Runtime>(
+  app: &AppHandle<R>,
+  mut request: Request,
+  event: &str,
+  window: Option<String>,
+  config: &Config,
+) {
+  let mut body = Vec::new();
+  let read = body_reader(&mut request, config)
+    .and_then(|mut r| r.read_to_end(&mut body).map_err(body_error));
+  if let Err(e) = read {
+    return respond_error(request, e, config);
+  }
+  let payload = if body.is_empty() {
+    JsonValue::Null
+  } else {
+    match serde_json::from_slice::<JsonValue>(&body) {
+      Ok(payload) => payload,
+      Err(e) => {
+        let error = HttpError::new(400, ErrorCode::InvalidBody, e.to_string());
+        return respond_error(request, error, config);
+      }
+    }
+  };
+
+  let origin = crate::header(&request, "Origin");
+  match emit_event(app, event, window.as_deref(), origin, payload, config) {
+    Ok(()) => {
+      let mut r = Response::empty(204u16);
+      cors(&request, &mut r, &config.allowed_origins);
+      let _ = request.respond(r);
+    }
+    Err(e) => respond_error(request, e, config),
+  }
+}
+
+/// Emits an event the clients of the origin may emit, only on the window if one is given.
+pub(crate) fn emit_event<R: Runtime>(
+  app: &AppHandle<R>,
+  event: &str,
+  window: Option<&str>,
+  origin: Option<&str>,
+  payload: JsonValue,
+  config: &Config,
+) -> Result<(), HttpError> {
+  if !valid_event_name(event) || !can_emit(config, origin, event) {
+    return Err(not_allowed(event));
+  }
+  let emitted = match window.and_then(|label| app.get_window(label)) {
+    Some(window) => window.emit_and_trigger(event, payload),
+    None => {
+      app.trigger_global(event, Some(payload.to_string()));
+      app.emit_all(event, payload)
+    }
+  };
+  emitted.map_err(|e| HttpError::new(500, ErrorCode::InternalError, e.to_string()))
+}
@@ -0,0 +1,123 @@
+// Copyright 2019-2021 Tauri Programme within The Commons Conservancy
+// SPDX-License-Identifier: Apache-2.0
+// SPDX-License-Identifier: MIT
+
+//! Response body formats negotiated with the `Accept` request header.
+
+use serde_json::Value as JsonValue;
+
+use crate::buffer;
+
+/// The format of a serialized command response.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Format {
+  Json,
+  #[cfg(feature = "msgpack")]
+  MessagePack,
+  #[cfg(feature = "cbor")]
+  Cbor,
+}
+
+impl Format {
+  fn from_media_type(media_type: &str) -> Option<Self> {
+    match media_type.to_ascii_lowercase().as_str() {
+      "application/json" | "application/*" | "*/*" => Some(Self::Json),
+      #[cfg(feature = "msgpack")]
+      "application/msgpack" | "application/x-msgpack" | "application/vnd.msgpack" => {
+        Some(Self::MessagePack)
+      }
+      #[cfg(feature = "cbor")]
+      "application/cbor" => Some(Self::Cbor),
+      _ => None,
+    }
+  }
+
+  /// Picks the format preferred by the `Accept` header, defaulting to JSON.
+  pub(crate) fn negotiate(accept: Option<&str>) -> Self {
+    let mut best: Option<(Self, f32)> = None;
+    for item in accept.unwrap_or_default().split(',') {
+      let mut pieces = item.split(';');
+      let format = match Self::from_media_type(pieces.next().unwrap_or_default().trim()) {
+        Some(format) => format,
+        None => continue,
+      };
+      let quality = pieces
+        .find_map(|p| p.trim().strip_prefix("q="))
+        .and_then(|q| q.trim().parse::<f32>().ok())
+        .unwrap_or(1.0);
+      if quality > 0.0 && best.map(|(_, q)| quality > q).unwrap_or(true) {
+        best.replace((format, quality));
+      }
+    }
+    best.map(|(format, _)| format).unwrap_or(Self::Json)
+  }
+
+  pub(crate) fn content_type(self) -> &'static str {
+    match self {
+      Self::Json => "application/json",
+      #[cfg(feature = "msgpack")]
+      Self::MessagePack => "application/msgpack",
+      #[cfg(feature = "cbor")]
+      Self::Cbor => "application/cbor",
+    }
+  }
+
+  /// Serializes the value into a pooled buffer.
+  pub(crate) fn serialize(self, value: &JsonValue) -> Vec<u8> {
+    let mut body = buffer::take();
+    match self {
+      Self::Json => serde_json::to_writer(&mut body, value).unwrap(),
+      #[cfg(feature = "msgpack")]
+      Self::MessagePack => rmp_serde::encode::write_named(&mut body, value).unwrap(),
+      #[cfg(feature = "cbor")]
+      Self::Cbor => ciborium::ser::into_writer(value, &mut body).unwrap(),
+    }
+    body
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn defaults_to_json() {
+    assert_eq!(Format::negotiate(None), Format::Json);
+    assert_eq!(Format::negotiate(Some("")), Format::Json);
+    assert_eq!(
+      Format::negotiate(Some("text/html, image/png")),
+      Format::Json
+    );
+    assert_eq!(Format::negotiate(Some("*/*")), Format::Json);
+  }
+
+  #[cfg(feature = "msgpack")]
+  #[test]
+  fn picks_the_preferred_format() {
+    assert_eq!(
+      Format::negotiate(Some("application/msgpack")),
+      Format::MessagePack
+    );
+    assert_eq!(
+      Format::negotiate(Some("application/json;q=0.5, application/X-MsgPack")),
+      Format::MessagePack
+    );
+    assert_eq!(
+      Format::negotiate(Some("application/msgpack;q=0.2, */*;q=0.8")),
+      Format::Json
+    );
+    assert_eq!(
+      Format::negotiate(Some("application/msgpack;q=0")),
+      Format::Json
+    );
+  }
+
+  #[cfg(feature = "cbor")]
+  #[test]
+  fn keeps_the_first_format_on_ties() {
+    assert_eq!(
+      Format::negotiate(Some("application/cbor, application/json")),
+      Format::Cbor
+    );
+  }
+}
@@ -0,0 +1,289 @@
+// Copyright 2019-2021 Tauri Programme within The Commons Conservancy
+// SPDX-License-Identifier: Apache-2.0
+// SPDX-License-Identifier: MIT
+
+//! Unary [gRPC-Web](https://github.com/grpc/grpc/blob/master/doc/PROTOCOL-WEB.md) calls
+//! on `POST /{window}/{cmd}`, or `POST /{cmd}` for the default window, identified by their `application/grpc-web*` content type.
+//!
+//! With the `+json` codec the message is the object of command arguments and the response is the
+//! JSON command output. With the protobuf codec the command receives the encoded message in the
+//! `body` argument as a [`crate::RawBody`] and returns the encoded response as bytes.
+//!
+//! Server streaming calls aren't mapped to channels yet: a call is answered with one message.
+
+use std::{
+  collections::HashMap,
+  io::Read,
+  str::FromStr,
+  sync::{
+    atomic::{AtomicUsize, Ordering},
+    Mutex,
+  },
+};
+
+use base64::Engine;
+use serde_json::{Map, Value as JsonValue};
+use tauri::{api::ipc::CallbackFn, AppHandle, Runtime};
+use tiny_http::{Header, Request, Response};
+
+use crate::{
+  batch::item_payload, body::RawBody, body_reader, cors, dispatch, header, merge_header_args,
+  query_args, request_url, route, Config,
+};
+
+/// The calls waiting for the command response, by callback id.
+pub(crate) type GrpcCalls = Mutex<HashMap<usize, (Request, Codec)>>;
+
+// https://grpc.github.io/grpc/core/md_doc_statuscodes.html
+const OK: u8 = 0;
+const UNKNOWN: u8 = 2;
+const INVALID_ARGUMENT: u8 = 3;
+const PERMISSION_DENIED: u8 = 7;
+const INTERNAL: u8 = 13;
+const UNIMPLEMENTED: u8 = 12;
+const UNAUTHENTICATED: u8 = 16;
+
+/// The message encoding of a call.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Codec {
+  json: bool,
+  /// `application/grpc-web-text`: the frames are base64 encoded.
+  text: bool,
+}
+
+impl Codec {
+  /// Reads the codec of a `Content-Type`, returning `None` for non gRPC-Web requests.
+  pub(crate) fn from_content_type(content_type: &str) -> Option<Self> {
+    let content_type = content_type.split(';').next().unwrap_or_default().trim();
+    let (text, subtype) =
+      if let Some(subtype) = content_type.strip_prefix("application/grpc-web-text") {
+        (true, subtype)
+      } else {
+        (false, content_type.strip_prefix("application/grpc-web")?)
+      };
+    let json = match subtype {
+      "" | "+proto" => false,
+      "+json" => true,
+      _ => return None,
+    };
+    Some(Self { json, text })
+  }
+
+  fn content_type(self) -> String {
+    format!(
+      "application/grpc-web{}{}",
+      if self.text { "-text" } else { "" },
+      if self.json { "+json" } else { "+proto" }
+    )
+  }
+}
+
+/// Frames a message or, with the `0x80` flag, the trailers.
+fn frame(flag: u8, data: &[u8]) -> Vec<u8> {
+  let mut frame = Vec::with_capacity(5 + data.len());
+  frame.push(flag);
+  frame.extend_from_slice(&(data.len() as u32).to_be_bytes());
+  frame.extend_from_slice(data);
+  frame
+}
+
+/// Reads the message of a unary call.
+fn read_message(body: Vec<u8>, codec: Codec) -> Result<Vec<u8>, String> {
+  let body = if codec.text {
+    let body: Vec<u8> = body
+      .into_iter()
+      .filter(|b| !b.is_ascii_whitespace())
+      .collect();
+    base64::engine::general_purpose::STANDARD
+      .decode(body)
+      .map_err(|e| e.to_string())?
+  } else {
+    body
+  };
+  if body.len() < 5 || body[0] != 0 {
+    return Err("expected a single uncompressed message".into());
+  }
+  let len = u32::from_be_bytes([body[1], body[2], body[3], body[4]]) as usize;
+  match body.len() - 5 {
+    rest if rest < len => Err("truncated message".into()),
+    rest if rest > len => Err("expected a single message".into()),
+    _ => Ok(body[5..].to_vec()),
+  }
+}
+
+/// Answers a call with an optional message and the status trailers.
+fn respond(
+  request: Request,
+  codec: Codec,
+  message: Option<&[u8]>,
+  status: u8,
+  error: &str,
+  config: &Config,
+) {
+  let mut body = message.map(|m| frame(0, m)).unwrap_or_default();
+  let mut trailers = format!("grpc-status: {}\r\n", status);
+  if !error.is_empty() {
+    // the message is percent encoded, keeping the printable ASCII characters
+    let mut encoded = String::new();
+    for b in error.bytes() {
+      if (0x20..0x7f).contains(&b) && b != b'%' {
+        encoded.push(b as char);
+      } else {
+        encoded.push_str(&format!("%{:02X}", b));
+      }
+    }
+    trailers.push_str(&format!("grpc-message: {}\r\n", encoded));
+  }
+  body.extend(frame(0x80, trailers.as_bytes()));
+  if codec.text {
+    body = base64::engine::general_purpose::STANDARD
+      .encode(body)
+      .into_bytes();
+  }
+  let mut r = Response::from_data(body)
+    .with_header(Header::from_bytes("Content-Type", codec.content_type()).unwrap())
+    .with_header(
+      Header::from_str("Access-Control-Expose-Headers: grpc-status, grpc-message").unwrap(),
+    );
+  cors(&request, &mut r, &config.allowed_origins);
+  let _ = request.respond(r);
+}
+
+/// Reads the call and dispatches it.
+pub(crate) fn handle<R: Runtime>(
+  app: &AppHandle<R>,
+  mut request: Request,
+  codec: Codec,
+  config: &Config,
+  next_id: &AtomicUsize,
+  pending: &GrpcCalls,
+) {
+  let url = match request_url(&request) {
+    Ok(url) => url,
+    Err(e) => return respond(request, codec, None, INVALID_ARGUMENT, &e.message, config),
+  };
+  let (window, cmd) = match route(app, header(&request, "Host"), url.path(), config) {
+    Ok(route) => route,
+    Err(e) => return respond(request, codec, None, UNIMPLEMENTED, &e.message, config),
+  };
+  let invoke_key = match query_args(&url) {
+    Ok((_, invoke_key)) => {
+      invoke_key.or_else(|| header(&request, "Tauri-Invoke-Key").map(Into::into))
+    }
+    Err(e) => return respond(request, codec, None, INVALID_ARGUMENT, &e, config),
+  };
+
+  let mut body = Vec::new();
+  let read = body_reader(&mut request, config)
+    .map_err(|e| e.message)
+    .and_then(|mut r| r.read_to_end(&mut body).map_err(|e| e.to_string()));
+  let message = match read.and_then(|_| read_message(body, codec)) {
+    Ok(message) => message,
+    Err(e) => return respond(request, codec, None, INVALID_ARGUMENT, &e, config),
+  };
+  let args = if codec.json {
+    match serde_json::from_slice(&message) {
+      Ok(JsonValue::Object(args)) => args,
+      _ => {
+        let error = "the message must be a JSON object";
+        return respond(request, codec, None, INVALID_ARGUMENT, error, config);
+      }
+    }
+  } else {
+    let mut args = Map::new();
+    args.insert(
+      "body".into(),
+      serde_json::to_value(RawBody::Bytes(message)).unwrap(),
+    );
+    args
+  };
+  let mut payload = match item_payload(cmd, args, invoke_key) {
+    Ok(payload) => payload,
+    Err(e) => {
+      return respond(
+        request,
+        codec,
+        None,
+        INVALID_ARGUMENT,
+        &e.to_string(),
+        config,
+      )
+    }
+  };
+  merge_header_args(&mut payload, request.headers(), config);
+
+  let id = next_id.fetch_add(1, Ordering::Relaxed);
+  payload.callback = CallbackFn(id);
+  payload.error = CallbackFn(id);
+  pending.lock().unwrap().insert(id, (request, codec));
+  if let Err(e) = dispatch::run(window, payload) {
+    if let Some((request, codec)) = pending.lock().unwrap().remove(&id) {
+      let status = match e.status {
+        401 => UNAUTHENTICATED,
+        403 => PERMISSION_DENIED,
+        _ => UNKNOWN,
+      };
+      respond(request, codec, None, status, &e.message, config);
+    }
+  }
+}
+
+/// Answers a call with the command response.
+pub(crate) fn complete(
+  request: Request,
+  codec: Codec,
+  response: Result<JsonValue, JsonValue>,
+  config: &Config,
+) {
+  match response {
+    Ok(value) if codec.json => {
+      let message = serde_json::to_vec(&value).unwrap();
+      respond(request, codec, Some(&message), OK, "", config)
+    }
+    Ok(value) => match serde_json::from_value::<Vec<u8>>(value) {
+      Ok(message) => respond(request, codec, Some(&message), OK, "", config),
+      Err(_) => {
+        let error = "the command response is not an encoded message";
+        respond(request, codec, None, INTERNAL, error, config)
+      }
+    },
+    Err(error) => {
+      let message = match error {
+        JsonValue::String(message) => message,
+        error => error.to_string(),
+      };
+      respond(request, codec, None, UNKNOWN, &message, config)
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  const BINARY: Codec = Codec {
+    json: false,
+    text: false,
+  };
+
+  #[test]
+  fn reads_a_single_message() {
+    assert_eq!(read_message(frame(0, b"hi"), BINARY).unwrap(), b"hi");
+    let text = Codec {
+      json: false,
+      text: true,
+    };
+    let body = base64::engine::general_purpose::STANDARD.encode(frame(0, b"hi"));
+    assert_eq!(read_message(body.into_bytes(), text).unwrap(), b"hi");
+  }
+
+  #[test]
+  fn rejects_truncated_and_trailing_data() {
+    let message = frame(0, b"hello");
+    assert!(read_message(message[..message.len() - 1].to_vec(), BINARY).is_err());
+    let mut messages = message.clone();
+    messages.extend(frame(0, b"again"));
+    assert!(read_message(messages, BINARY).is_err());
+    assert!(read_message(frame(1, b"hello"), BINARY).is_err());
+  }
+}
@@ -0,0 +1,24 @@
+// Copyright 2019-2021 Tauri Programme within The Commons Conservancy
+// SPDX-License-Identifier: Apache-2.0
+// SPDX-License-Identifier: MIT
+
+//! The health check `GET /__health`, answered with `204 No Content` once the server accepts
+//! connections, which the initialization script polls while the server is rebuilt.
+
+use tiny_http::{Header, Method, Request, Response};
+
+use crate::{batch, cors, Config, HttpError};
+
+pub(crate) const PATH: &str = "/__health";
+
+/// Answers `GET /__health`.
+pub(crate) fn handle(request: Request, config: &Config) {
+  if !matches!(request.method(), Method::Get | Method::Head) {
+    let error = HttpError::method_not_allowed("GET, HEAD, OPTIONS");
+    return batch::respond_error(request, error, config);
+  }
+  let mut r =
+    Response::empty(204u16).with_header(Header::from_bytes("Cache-Control", "no-store").unwrap());
+  cors(&request, &mut r, &config.allowed_origins);
+  let _ = request.respond(r);
+}
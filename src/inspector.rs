@@ -0,0 +1,153 @@
+// Copyright 2019-2021 Tauri Programme within The Commons Conservancy
+// SPDX-License-Identifier: Apache-2.0
+// SPDX-License-Identifier: MIT
+
+//! The request inspector: the recent invokes, kept in memory for a devtools panel and listed by
+//! `GET /__inspector` with the `Authorization: Bearer` token of `Invoke::inspector`.
+//!
+//! Each entry has a sequence number, so a live panel polls `GET /__inspector?after={seq}`
+//! for the invokes completed since the last entry it got.
+
+use std::{
+  collections::{HashSet, VecDeque},
+  sync::Mutex,
+};
+
+use serde_json::{json, Value as JsonValue};
+use tiny_http::{Header, Method, Request, Response};
+
+use crate::{
+  access_log, batch, cors, header, request_url, trace::RequestRecord, Config, ErrorCode, HttpError,
+};
+
+const REDACTED: &str = "[REDACTED]";
+
+pub(crate) struct Inspector {
+  capacity: usize,
+  token: String,
+  /// The names of the redacted arguments, if the arguments are recorded.
+  args: Option<HashSet<String>>,
+  entries: Mutex<Entries>,
+}
+
+#[derive(Default)]
+struct Entries {
+  next_seq: u64,
+  entries: VecDeque<(u64, JsonValue)>,
+}
+
+impl Inspector {
+  pub(crate) fn new(capacity: usize, token: String, args: Option<HashSet<String>>) -> Self {
+    Self {
+      capacity,
+      token,
+      args,
+      entries: Default::default(),
+    }
+  }
+
+  /// The arguments of an invoke as recorded, if they are.
+  pub(crate) fn redact(&self, args: &JsonValue) -> Option<JsonValue> {
+    let redacted = self.args.as_ref()?;
+    let mut args = args.clone();
+    redact(&mut args, redacted);
+    Some(args)
+  }
+
+  /// Keeps a completed invoke, dropping the oldest one past the capacity.
+  pub(crate) fn record(&self, record: &RequestRecord, args: Option<JsonValue>) {
+    let cmd = match &record.cmd {
+      Some(cmd) => cmd,
+      None => return,
+    };
+    let mut entries = self.entries.lock().unwrap();
+    let seq = entries.next_seq;
+    entries.next_seq += 1;
+    let entry = json!({
+      "seq": seq,
+      "requestId": record.request_id,
+      "timestamp": access_log::rfc3339(access_log::started(record)),
+      "window": record.window,
+      "cmd": cmd,
+      "args": args,
+      "status": record.status,
+      "bytes": record.bytes,
+      "durationMs": record.duration.as_secs_f64() * 1000.0,
+      "client": record.client.map(|client| client.to_string()),
+      "origin": record.origin,
+    });
+    entries.entries.push_back((seq, entry));
+    while entries.entries.len() > self.capacity {
+      entries.entries.pop_front();
+    }
+  }
+
+  /// Answers `GET /__inspector` with the recorded invokes, oldest first.
+  pub(crate) fn handle(&self, request: Request, config: &Config) {
+    if !matches!(request.method(), Method::Get | Method::Head) {
+      let error = HttpError::method_not_allowed("GET, HEAD, OPTIONS");
+      return batch::respond_error(request, error, config);
+    }
+    let token = header(&request, "Authorization").and_then(|value| value.strip_prefix("Bearer "));
+    if !token.map_or(false, |token| token_matches(token.trim(), &self.token)) {
+      let error = HttpError::new(
+        401,
+        ErrorCode::InvalidToken,
+        "the inspector requires its `Authorization: Bearer` token",
+      );
+      return batch::respond_error(request, error, config);
+    }
+    let after = request_url(&request).ok().and_then(|url| {
+      url
+        .query_pairs()
+        .find(|(name, _)| name == "after")
+        .and_then(|(_, value)| value.parse::<u64>().ok())
+    });
+    let body = {
+      let entries = self.entries.lock().unwrap();
+      let entries = entries
+        .entries
+        .iter()
+        .filter(|(seq, _)| after.map_or(true, |after| *seq > after))
+        .map(|(_, entry)| entry)
+        .collect::<Vec<_>>();
+      serde_json::to_vec(&entries).unwrap()
+    };
+    let mut r = Response::from_data(body)
+      .with_header(Header::from_bytes("Content-Type", "application/json").unwrap())
+      .with_header(Header::from_bytes("Cache-Control", "no-store").unwrap());
+    cors(&request, &mut r, &config.allowed_origins);
+    let _ = request.respond(r);
+  }
+}
+
+/// Compares the tokens in constant time for tokens of the same length.
+pub(crate) fn token_matches(token: &str, expected: &str) -> bool {
+  token.len() == expected.len()
+    && token
+      .bytes()
+      .zip(expected.bytes())
+      .fold(0, |diff, (a, b)| diff | (a ^ b))
+      == 0
+}
+
+/// Replaces the values of the redacted object keys, at any depth.
+fn redact(value: &mut JsonValue, redacted: &HashSet<String>) {
+  match value {
+    JsonValue::Object(object) => {
+      for (key, value) in object.iter_mut() {
+        if redacted.contains(key) {
+          *value = REDACTED.into();
+        } else {
+          redact(value, redacted);
+        }
+      }
+    }
+    JsonValue::Array(values) => {
+      for value in values {
+        redact(value, redacted);
+      }
+    }
+    _ => {}
+  }
+}
@@ -0,0 +1,144 @@
+// Copyright 2019-2021 Tauri Programme within The Commons Conservancy
+// SPDX-License-Identifier: Apache-2.0
+// SPDX-License-Identifier: MIT
+
+//! The instance file of `Invoke::single_instance`, telling the later instances of the app
+//! where the server of the first one listens.
+//!
+//! The file holds the port and process id of the instance with a random key. The instance
+//! answers `GET /__instance` with its process id to the requests bearing the key, so the file
+//! of an instance that exited, whose port another server may since listen on, is told apart
+//! from the file of a running one.
+
+use std::{
+  fs::{self, OpenOptions},
+  io::{self, Read, Write},
+  net::{TcpStream, ToSocketAddrs},
+  path::Path,
+  time::Duration,
+};
+
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use tiny_http::{Header, Method, Request, Response};
+
+use crate::{batch, cors, header, inspector::token_matches, Config, ErrorCode, HttpError};
+
+/// The time given to the first instance to answer the handshake.
+const PROBE_TIMEOUT: Duration = Duration::from_secs(1);
+
+#[derive(Serialize, Deserialize)]
+struct InstanceFile {
+  port: u16,
+  pid: u32,
+  key: String,
+}
+
+/// The running instance of the app that owns the invoke server, see `Invoke::single_instance`.
+#[derive(Debug, Clone)]
+#[non_exhaustive]
+pub struct PrimaryInstance {
+  /// The port of its invoke server.
+  pub port: u16,
+  /// Its process id.
+  pub pid: u32,
+}
+
+impl PrimaryInstance {
+  /// The base URL of its invokes, such as `http://localhost:8080`.
+  pub fn url(&self) -> String {
+    format!("http://localhost:{}", self.port)
+  }
+}
+
+/// The instance of the file, if it is running.
+pub(crate) fn probe(path: &Path) -> Option<PrimaryInstance> {
+  let file: InstanceFile = serde_json::from_slice(&fs::read(path).ok()?).ok()?;
+  match handshake(&file) {
+    Ok(pid) if pid == file.pid => Some(PrimaryInstance {
+      port: file.port,
+      pid,
+    }),
+    _ => None,
+  }
+}
+
+/// Asks the server of the file for its process id.
+fn handshake(file: &InstanceFile) -> io::Result<u32> {
+  let mut stream = connect(file.port)?;
+  stream.set_read_timeout(Some(PROBE_TIMEOUT))?;
+  stream.set_write_timeout(Some(PROBE_TIMEOUT))?;
+  write!(
+    stream,
+    "GET /__instance HTTP/1.1\r\nHost: localhost:{}\r\nAuthorization: Bearer {}\r\nConnection: close\r\n\r\n",
+    file.port, file.key
+  )?;
+  stream.flush()?;
+
+  let mut response = Vec::new();
+  stream.read_to_end(&mut response)?;
+  let invalid = || io::Error::new(io::ErrorKind::InvalidData, "not an invoke server instance");
+  if !response.starts_with(b"HTTP/1.1 200 ") {
+    return Err(invalid());
+  }
+  let body = response
+    .windows(4)
+    .position(|w| w == b"\r\n\r\n")
+    .map(|at| &response[at + 4..])
+    .ok_or_else(invalid)?;
+  serde_json::from_slice::<serde_json::Value>(body)
+    .ok()
+    .and_then(|body| body["pid"].as_u64())
+    .map(|pid| pid as u32)
+    .ok_or_else(invalid)
+}
+
+fn connect(port: u16) -> io::Result<TcpStream> {
+  let mut error = io::Error::new(io::ErrorKind::NotFound, "localhost has no address");
+  for address in ("localhost", port).to_socket_addrs()? {
+    match TcpStream::connect_timeout(&address, PROBE_TIMEOUT) {
+      Ok(stream) => return Ok(stream),
+      Err(e) => error = e,
+    }
+  }
+  Err(error)
+}
+
+/// Writes the instance file of the server, readable by the current user only.
+pub(crate) fn write(path: &Path, port: u16, key: &str) -> io::Result<()> {
+  let file = InstanceFile {
+    port,
+    pid: std::process::id(),
+    key: key.to_string(),
+  };
+  let mut options = OpenOptions::new();
+  options.write(true).create(true).truncate(true);
+  #[cfg(unix)]
+  std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+  options
+    .open(path)?
+    .write_all(&serde_json::to_vec(&file).unwrap())
+}
+
+/// Answers the `GET /__instance` handshake of the later instances.
+pub(crate) fn handle(request: Request, key: &str, config: &Config) {
+  if !matches!(request.method(), Method::Get | Method::Head) {
+    let error = HttpError::method_not_allowed("GET, HEAD, OPTIONS");
+    return batch::respond_error(request, error, config);
+  }
+  let token = header(&request, "Authorization").and_then(|value| value.strip_prefix("Bearer "));
+  if !token.map_or(false, |token| token_matches(token.trim(), key)) {
+    let error = HttpError::new(
+      401,
+      ErrorCode::InvalidToken,
+      "the instance handshake requires the key of the instance file",
+    );
+    return batch::respond_error(request, error, config);
+  }
+  let body = json!({ "pid": std::process::id() });
+  let mut r = Response::from_data(serde_json::to_vec(&body).unwrap())
+    .with_header(Header::from_bytes("Content-Type", "application/json").unwrap())
+    .with_header(Header::from_bytes("Cache-Control", "no-store").unwrap());
+  cors(&request, &mut r, &config.allowed_origins);
+  let _ = request.respond(r);
+}
@@ -0,0 +1,219 @@
+// Copyright 2019-2021 Tauri Programme within The Commons Conservancy
+// SPDX-License-Identifier: Apache-2.0
+// SPDX-License-Identifier: MIT
+
+//! Long-running commands answered with `202 Accepted` and polled on `/__jobs/{id}`.
+
+use std::{
+  collections::HashMap,
+  str::FromStr,
+  sync::Mutex,
+  time::{Duration, Instant},
+};
+
+use serde_json::{json, Value as JsonValue};
+use tiny_http::{Header, Method, Request, Response};
+
+use crate::{
+  batch::respond_error, cancel::Cancellations, cors, inspector::token_matches, random_token,
+  request_url, respond_raw, respond_value, send_file, Config, ErrorCode, HttpError, RawResponse,
+};
+
+/// The default time finished jobs are kept for.
+pub(crate) const DEFAULT_TTL: Duration = Duration::from_secs(10 * 60);
+
+enum JobState {
+  /// The job runs the invoke with this callback id.
+  Running(usize),
+  Finished {
+    response: Result<JsonValue, JsonValue>,
+    /// The file of a [`RawResponse`] result, read from the response when it is stored.
+    raw: Option<RawResponse>,
+    at: Instant,
+  },
+}
+
+struct Job {
+  state: JobState,
+  /// The `__TAURI_INVOKE_KEY__` of the invoke, required to read or cancel the job.
+  invoke_key: String,
+}
+
+/// Removes the file of a [`RawResponse`] result no longer delivered, if it should be removed.
+fn remove_file(raw: &Option<RawResponse>) {
+  if let Some(raw) = raw.as_ref().filter(|raw| raw.should_remove_file()) {
+    let _ = std::fs::remove_file(raw.path());
+  }
+}
+
+/// The jobs of the commands configured with `Invoke::job_commands`.
+#[derive(Default)]
+pub(crate) struct Jobs {
+  jobs: Mutex<HashMap<String, Job>>,
+  /// The job of each running invoke, by callback id.
+  invokes: Mutex<HashMap<usize, String>>,
+}
+
+impl Jobs {
+  /// Registers the job of the invoke and answers the request with its location.
+  pub(crate) fn start(
+    &self,
+    request: Request,
+    invoke_id: usize,
+    invoke_key: String,
+    config: &Config,
+  ) {
+    let id = random_token();
+
+    let now = Instant::now();
+    let mut jobs = self.jobs.lock().unwrap();
+    jobs.retain(|_, job| match &job.state {
+      JobState::Running(_) => true,
+      JobState::Finished { raw, at, .. } => {
+        let keep = now.duration_since(*at) < config.job_ttl;
+        if !keep {
+          remove_file(raw);
+        }
+        keep
+      }
+    });
+    jobs.insert(
+      id.clone(),
+      Job {
+        state: JobState::Running(invoke_id),
+        invoke_key,
+      },
+    );
+    drop(jobs);
+    self.invokes.lock().unwrap().insert(invoke_id, id.clone());
+
+    let location = format!("/__jobs/{}", id);
+    let mut r =
+      Response::from_data(serde_json::to_vec(&json!({ "id": id, "status": "running" })).unwrap())
+        .with_status_code(202)
+        .with_header(Header::from_str("Content-Type: application/json").unwrap())
+        .with_header(Header::from_bytes("Location", location).unwrap())
+        .with_header(Header::from_str("Access-Control-Expose-Headers: Location").unwrap());
+    cors(&request, &mut r, &config.allowed_origins);
+    let _ = request.respond(r);
+  }
+
+  /// Returns the job of the invoke, if it has one.
+  pub(crate) fn take_invoke(&self, invoke_id: usize) -> Option<String> {
+    self.invokes.lock().unwrap().remove(&invoke_id)
+  }
+
+  /// Stores the response of a job.
+  pub(crate) fn complete(&self, id: &str, response: Result<JsonValue, JsonValue>) {
+    // cancelled jobs are gone, their response is dropped
+    let raw = response.as_ref().ok().and_then(RawResponse::from_value);
+    if let Some(job) = self.jobs.lock().unwrap().get_mut(id) {
+      job.state = JobState::Finished {
+        response,
+        raw,
+        at: Instant::now(),
+      };
+    }
+  }
+
+  /// Handles a request to the `/__jobs/{id}` routes.
+  pub(crate) fn handle(&self, request: Request, cancellations: &Cancellations, config: &Config) {
+    let url = match request_url(&request) {
+      Ok(url) => url,
+      Err(e) => return respond_error(request, e, config),
+    };
+    let id = url.path().trim_start_matches("/__jobs/").to_string();
+    let invoke_key = url
+      .query_pairs()
+      .find(|(key, _)| key == "__TAURI_INVOKE_KEY__")
+      .map(|(_, value)| value.into_owned());
+    let not_found = || {
+      HttpError::new(
+        404,
+        ErrorCode::UnknownJob,
+        format!("job `{}` not found", id),
+      )
+    };
+    // a job is only reachable with the key of the invoke starting it
+    let authorize = |job: &Job| match invoke_key.as_deref() {
+      Some(sent) if token_matches(sent, &job.invoke_key) => Ok(()),
+      Some(_) => Err(HttpError::new(
+        403,
+        ErrorCode::InvalidInvokeKey,
+        "the `__TAURI_INVOKE_KEY__` of the request is not the one of the job",
+      )),
+      None => Err(HttpError::new(
+        401,
+        ErrorCode::MissingInvokeKey,
+        "the request has no `__TAURI_INVOKE_KEY__`",
+      )),
+    };
+
+    match request.method() {
+      Method::Get | Method::Head => {
+        let mut jobs = self.jobs.lock().unwrap();
+        let job = match jobs.get(&id) {
+          None => {
+            drop(jobs);
+            return respond_error(request, not_found(), config);
+          }
+          Some(job) => job,
+        };
+        if let Err(e) = authorize(job) {
+          drop(jobs);
+          return respond_error(request, e, config);
+        }
+        let (status, result) = match &job.state {
+          JobState::Running(_) => ("running", None),
+          JobState::Finished { raw: Some(raw), .. } => {
+            let raw = raw.clone();
+            // the file is gone once sent, so the job is only delivered once
+            if raw.should_remove_file() && request.method() == &Method::Get {
+              jobs.remove(&id);
+              drop(jobs);
+              return respond_raw(request, raw, Vec::new(), config);
+            }
+            drop(jobs);
+            return send_file(request, &raw, Vec::new(), config);
+          }
+          JobState::Finished { response, .. } => match response {
+            Ok(value) => ("completed", Some(("result", value.clone()))),
+            Err(error) => ("failed", Some(("error", error.clone()))),
+          },
+        };
+        drop(jobs);
+        let mut body = json!({ "id": id, "status": status });
+        if let Some((field, value)) = result {
+          body[field] = value;
+        }
+        respond_value(request, "", Ok(body), Vec::new(), config);
+      }
+      Method::Delete => {
+        let mut jobs = self.jobs.lock().unwrap();
+        let checked = match jobs.get(&id) {
+          None => Err(not_found()),
+          Some(job) => authorize(job),
+        };
+        if let Err(e) = checked {
+          drop(jobs);
+          return respond_error(request, e, config);
+        }
+        let job = jobs.remove(&id).unwrap();
+        drop(jobs);
+        match job.state {
+          // the command is told through its `Cancellation` argument, if any
+          JobState::Running(invoke_id) => cancellations.cancel(invoke_id),
+          JobState::Finished { raw, .. } => remove_file(&raw),
+        }
+        let mut r = Response::empty(204u16);
+        cors(&request, &mut r, &config.allowed_origins);
+        let _ = request.respond(r);
+      }
+      _ => respond_error(
+        request,
+        HttpError::method_not_allowed("GET, HEAD, DELETE, OPTIONS"),
+        config,
+      ),
+    }
+  }
+}
@@ -0,0 +1,63 @@
+// Copyright 2019-2021 Tauri Programme within The Commons Conservancy
+// SPDX-License-Identifier: Apache-2.0
+// SPDX-License-Identifier: MIT
+
+//! The plugin of `Invoke::plugin`, with the commands the app's own UI uses to show the status
+//! of the server.
+//!
+//! The allowed origins are only replaced from Rust, with `Invoke::origins`: every command is
+//! reachable by the clients of the server holding the invoke key.
+
+use std::sync::Arc;
+
+use serde::Serialize;
+use tauri::{
+  plugin::{Builder, TauriPlugin},
+  Manager, Runtime, State,
+};
+
+use crate::{origins::AllowedOrigins, ConnectionInfo, ServerState};
+
+const PLUGIN_NAME: &str = "invoke-http";
+
+/// The state of the commands, managed by the plugin.
+pub(crate) struct Management {
+  pub(crate) state: Arc<ServerState>,
+  pub(crate) origins: AllowedOrigins,
+  pub(crate) port: u16,
+  pub(crate) websocket_port: Option<u16>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct Status {
+  /// Whether the server started listening.
+  listening: bool,
+  #[serde(flatten)]
+  connection: ConnectionInfo,
+  allowed_origins: Vec<String>,
+  /// The open event stream and WebSocket connections.
+  event_connections: usize,
+}
+
+#[tauri::command]
+fn status(management: State<'_, Management>) -> Status {
+  Status {
+    listening: management.state.port.lock().unwrap().is_some(),
+    connection: management
+      .state
+      .connection_info(management.port, management.websocket_port),
+    allowed_origins: management.origins.get(),
+    event_connections: management.state.events.subscriber_count(),
+  }
+}
+
+pub(crate) fn plugin<R: Runtime>(management: Management) -> TauriPlugin<R> {
+  Builder::new(PLUGIN_NAME)
+    .invoke_handler(tauri::generate_handler![status])
+    .setup(move |app| {
+      app.manage(management);
+      Ok(())
+    })
+    .build()
+}
@@ -0,0 +1,298 @@
+// Copyright 2019-2021 Tauri Programme within The Commons Conservancy
+// SPDX-License-Identifier: Apache-2.0
+// SPDX-License-Identifier: MIT
+
+//! [Prometheus](https://prometheus.io/docs/instrumenting/exposition_formats/) metrics of the
+//! requests, served on `GET /__metrics` with `Invoke::metrics` or on their own port with
+//! `Invoke::metrics_server`.
+//!
+//! `tiny_http` doesn't expose its connections, so only the event stream and WebSocket
+//! connections are counted.
+//!
+//! The invoke latencies are also recorded without them, for `Invoke::stats`.
+
+use std::{
+  collections::BTreeMap,
+  fmt::Write,
+  sync::{
+    atomic::{AtomicUsize, Ordering},
+    Arc, Mutex,
+  },
+  time::Duration,
+};
+
+use tiny_http::{Header, Method, Request, Response};
+
+use crate::{batch, buffer, cors, events::EventHub, trace::RequestRecord, Config, HttpError};
+
+const CONTENT_TYPE: &str = "text/plain; version=0.0.4";
+/// The upper bounds of the body size buckets, in bytes.
+const BYTE_BUCKETS: [f64; 9] = [
+  256.0, 1024.0, 4096.0, 16384.0, 65536.0, 262144.0, 1048576.0, 4194304.0, 16777216.0,
+];
+/// The upper bounds of the latency buckets, in seconds.
+const LATENCY_BUCKETS: [f64; 12] = [
+  0.001, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+
+/// A histogram with cumulative buckets.
+struct Histogram<const N: usize> {
+  buckets: [u64; N],
+  sum: f64,
+  count: u64,
+}
+
+impl<const N: usize> Default for Histogram<N> {
+  fn default() -> Self {
+    Self {
+      buckets: [0; N],
+      sum: 0.0,
+      count: 0,
+    }
+  }
+}
+
+impl<const N: usize> Histogram<N> {
+  fn observe(&mut self, bounds: &[f64; N], value: f64) {
+    for (count, bound) in self.buckets.iter_mut().zip(bounds) {
+      if value <= *bound {
+        *count += 1;
+      }
+    }
+    self.sum += value;
+    self.count += 1;
+  }
+
+  /// Renders the samples of the histogram, `labels` being empty or ending with a comma.
+  fn render(&self, out: &mut String, name: &str, labels: &str, bounds: &[f64; N]) {
+    for (count, bound) in self.buckets.iter().zip(bounds) {
+      let _ = writeln!(
+        out,
+        "{}_bucket{{{}le=\"{}\"}} {}",
+        name, labels, bound, count
+      );
+    }
+    let _ = writeln!(
+      out,
+      "{}_bucket{{{}le=\"+Inf\"}} {}",
+      name, labels, self.count
+    );
+    let labels = labels.trim_end_matches(',');
+    let _ = writeln!(out, "{}_sum{{{}}} {}", name, labels, self.sum);
+    let _ = writeln!(out, "{}_count{{{}}} {}", name, labels, self.count);
+  }
+}
+
+#[derive(Default)]
+struct Latency {
+  histogram: Histogram<12>,
+  max: Duration,
+}
+
+#[derive(Default)]
+struct Samples {
+  /// The completed requests, by command (empty for other requests) and status.
+  requests: BTreeMap<(String, Option<u16>), u64>,
+  request_bytes: Histogram<9>,
+  response_bytes: Histogram<9>,
+  /// The time from the dispatch of the invokes to their response, by command.
+  latencies: BTreeMap<String, Latency>,
+}
+
+/// The metrics of the server.
+#[derive(Default)]
+pub(crate) struct Metrics {
+  samples: Mutex<Samples>,
+  in_flight: AtomicUsize,
+}
+
+impl Metrics {
+  /// Counts a request being handled.
+  pub(crate) fn start(&self) {
+    self.in_flight.fetch_add(1, Ordering::Relaxed);
+  }
+
+  /// Records a completed request.
+  pub(crate) fn finish(&self, record: &RequestRecord) {
+    self.in_flight.fetch_sub(1, Ordering::Relaxed);
+    let mut samples = self.samples.lock().unwrap();
+    let key = (record.cmd.clone().unwrap_or_default(), record.status);
+    *samples.requests.entry(key).or_default() += 1;
+    if let Some(bytes) = record.request_bytes {
+      samples.request_bytes.observe(&BYTE_BUCKETS, bytes as f64);
+    }
+    if let Some(bytes) = record.bytes {
+      samples.response_bytes.observe(&BYTE_BUCKETS, bytes as f64);
+    }
+  }
+
+  /// Records the time from the dispatch of an invoke to the response of its command.
+  pub(crate) fn observe_latency(&self, cmd: &str, latency: Duration) {
+    let mut samples = self.samples.lock().unwrap();
+    let entry = match samples.latencies.get_mut(cmd) {
+      Some(entry) => entry,
+      None => samples.latencies.entry(cmd.to_string()).or_default(),
+    };
+    entry
+      .histogram
+      .observe(&LATENCY_BUCKETS, latency.as_secs_f64());
+    entry.max = entry.max.max(latency);
+  }
+
+  /// Renders the metrics in the Prometheus text format.
+  pub(crate) fn render(&self, events: &EventHub) -> String {
+    let mut out = String::new();
+    let samples = self.samples.lock().unwrap();
+
+    out.push_str("# HELP tauri_invoke_http_requests_total The completed requests.\n");
+    out.push_str("# TYPE tauri_invoke_http_requests_total counter\n");
+    for ((cmd, status), count) in &samples.requests {
+      let status = status.map_or_else(|| "unknown".to_string(), |s| s.to_string());
+      let _ = writeln!(
+        out,
+        "tauri_invoke_http_requests_total{{cmd=\"{}\",status=\"{}\"}} {}",
+        escape(cmd),
+        status,
+        count
+      );
+    }
+
+    out.push_str("# HELP tauri_invoke_http_requests_in_flight The requests being handled.\n");
+    out.push_str("# TYPE tauri_invoke_http_requests_in_flight gauge\n");
+    let _ = writeln!(
+      out,
+      "tauri_invoke_http_requests_in_flight {}",
+      self.in_flight.load(Ordering::Relaxed)
+    );
+
+    out.push_str(
+      "# HELP tauri_invoke_http_request_body_bytes The size of the request bodies with a known length.\n",
+    );
+    out.push_str("# TYPE tauri_invoke_http_request_body_bytes histogram\n");
+    samples.request_bytes.render(
+      &mut out,
+      "tauri_invoke_http_request_body_bytes",
+      "",
+      &BYTE_BUCKETS,
+    );
+
+    out.push_str(
+      "# HELP tauri_invoke_http_response_body_bytes The size of the response bodies with a known length.\n",
+    );
+    out.push_str("# TYPE tauri_invoke_http_response_body_bytes histogram\n");
+    samples.response_bytes.render(
+      &mut out,
+      "tauri_invoke_http_response_body_bytes",
+      "",
+      &BYTE_BUCKETS,
+    );
+
+    out.push_str(
+      "# HELP tauri_invoke_http_event_connections The open event stream and WebSocket connections.\n",
+    );
+    out.push_str("# TYPE tauri_invoke_http_event_connections gauge\n");
+    let _ = writeln!(
+      out,
+      "tauri_invoke_http_event_connections {}",
+      events.subscriber_count()
+    );
+
+    out.push_str(
+      "# HELP tauri_invoke_http_invoke_duration_seconds The time from the dispatch of the invokes to the response of their command.\n",
+    );
+    out.push_str("# TYPE tauri_invoke_http_invoke_duration_seconds histogram\n");
+    for (cmd, latency) in &samples.latencies {
+      latency.histogram.render(
+        &mut out,
+        "tauri_invoke_http_invoke_duration_seconds",
+        &format!("cmd=\"{}\",", escape(cmd)),
+        &LATENCY_BUCKETS,
+      );
+    }
+    out
+  }
+}
+
+/// The latency of the invokes of a command, see [`InvokeStats::commands`].
+#[derive(Debug, Clone)]
+#[non_exhaustive]
+pub struct CommandStats {
+  pub cmd: String,
+  /// The number of invokes the command responded to.
+  pub count: u64,
+  /// The total time from the dispatch of the invokes to the response of the command.
+  pub total: Duration,
+  /// The longest time from the dispatch of an invoke to the response of the command.
+  pub max: Duration,
+}
+
+impl CommandStats {
+  /// The average time from the dispatch of an invoke to the response of the command.
+  pub fn mean(&self) -> Duration {
+    if self.count == 0 {
+      return Duration::default();
+    }
+    Duration::from_secs_f64(self.total.as_secs_f64() / self.count as f64)
+  }
+}
+
+/// A handle to the statistics of the server, see `Invoke::stats`.
+#[derive(Clone)]
+pub struct InvokeStats(pub(crate) Arc<Metrics>);
+
+impl InvokeStats {
+  /// The latency of the invokes answered so far, by command name.
+  pub fn commands(&self) -> Vec<CommandStats> {
+    let samples = self.0.samples.lock().unwrap();
+    samples
+      .latencies
+      .iter()
+      .map(|(cmd, latency)| CommandStats {
+        cmd: cmd.clone(),
+        count: latency.histogram.count,
+        total: Duration::from_secs_f64(latency.histogram.sum),
+        max: latency.max,
+      })
+      .collect()
+  }
+}
+
+/// Escapes a label value.
+fn escape(value: &str) -> String {
+  value
+    .replace('\\', "\\\\")
+    .replace('"', "\\\"")
+    .replace('\n', "\\n")
+}
+
+/// Answers `GET /__metrics` on the invoke port.
+pub(crate) fn handle(request: Request, metrics: &Metrics, events: &EventHub, config: &Config) {
+  if !matches!(request.method(), Method::Get | Method::Head) {
+    let error = HttpError::method_not_allowed("GET, HEAD, OPTIONS");
+    return batch::respond_error(request, error, config);
+  }
+  let mut r = response(metrics, events);
+  cors(&request, &mut r, &config.allowed_origins);
+  let _ = request.respond(r);
+}
+
+fn response(metrics: &Metrics, events: &EventHub) -> Response<buffer::PooledBody> {
+  let mut body = buffer::take();
+  body.extend_from_slice(metrics.render(events).as_bytes());
+  buffer::response(body).with_header(Header::from_bytes("Content-Type", CONTENT_TYPE).unwrap())
+}
+
+/// Serves `GET /metrics` on its own port, on a background thread.
+pub(crate) fn serve(port: u16, metrics: Arc<Metrics>, events: Arc<EventHub>) {
+  let server = tiny_http::Server::http(format!("localhost:{}", port)).unwrap();
+  std::thread::spawn(move || {
+    for request in server.incoming_requests() {
+      let path = request.url().split('?').next().unwrap_or_default();
+      let r = match (request.method(), path) {
+        (Method::Get | Method::Head, "/metrics") => response(&metrics, &events),
+        _ => buffer::response(Vec::new()).with_status_code(404),
+      };
+      let _ = request.respond(r);
+    }
+  });
+}
@@ -0,0 +1,140 @@
+// Copyright 2019-2021 Tauri Programme within The Commons Conservancy
+// SPDX-License-Identifier: Apache-2.0
+// SPDX-License-Identifier: MIT
+
+//! The allowed origins, replaceable while the server runs, and those of the Tauri config.
+
+use std::sync::{Arc, RwLock};
+
+use tauri::utils::config::{AppUrl, WindowUrl};
+use tauri::Url;
+
+#[derive(Default)]
+struct Origins {
+  /// The origins of the app, replaced as a whole.
+  configured: Vec<String>,
+  /// The origins allowed by the server itself, such as the public URL of the tunnel,
+  /// kept when the configured origins are replaced.
+  pinned: Vec<String>,
+}
+
+/// The allowed origins, shared by the clones of the config.
+#[derive(Clone, Default)]
+pub(crate) struct AllowedOrigins(Arc<RwLock<Origins>>);
+
+impl AllowedOrigins {
+  pub(crate) fn new(origins: Vec<String>) -> Self {
+    Self(Arc::new(RwLock::new(Origins {
+      configured: origins,
+      pinned: Vec::new(),
+    })))
+  }
+
+  /// Whether every origin is allowed, with `*`.
+  pub(crate) fn any(&self) -> bool {
+    self.contains("*")
+  }
+
+  pub(crate) fn contains(&self, origin: &str) -> bool {
+    let origins = self.0.read().unwrap();
+    origins
+      .configured
+      .iter()
+      .chain(&origins.pinned)
+      .any(|o| o == origin)
+  }
+
+  /// The configured origins.
+  pub(crate) fn get(&self) -> Vec<String> {
+    self.0.read().unwrap().configured.clone()
+  }
+
+  /// Replaces the configured origins at once, for the requests read from then on.
+  pub(crate) fn set(&self, origins: Vec<String>) {
+    self.0.write().unwrap().configured = origins;
+  }
+
+  /// Allows an origin of the server itself.
+  pub(crate) fn pin(&self, origin: String) {
+    self.0.write().unwrap().pinned.push(origin);
+  }
+}
+
+/// A handle to the allowed origins of the server, see `Invoke::origins`.
+#[derive(Clone)]
+pub struct InvokeOrigins(pub(crate) AllowedOrigins);
+
+impl InvokeOrigins {
+  /// The allowed origins, without those allowed by the server itself such as the tunnel URL.
+  pub fn get(&self) -> Vec<String> {
+    self.0.get()
+  }
+
+  /// Replaces the allowed origins at once, like the config reload, rejecting invalid origins.
+  pub fn set(&self, origins: Vec<String>) -> Result<(), String> {
+    if let Some(origin) = origins
+      .iter()
+      .find(|origin| *origin != "*" && !crate::valid_origin(origin))
+    {
+      return Err(format!("invalid origin `{}`", origin));
+    }
+    self.0.set(origins);
+    Ok(())
+  }
+}
+
+/// The origins the windows of the app load their content from, to pass to `Invoke::new`,
+/// so they stay in sync with `tauri.conf.json`:
+///
+/// - In `dev` builds, the origin of the `build.devPath` URL, or the custom protocol origin
+///   if the frontend is bundled.
+/// - In release builds, the origin of the `build.distDir` URL, or the custom protocol origin,
+///   `tauri://localhost` or `https://tauri.localhost` on Windows.
+/// - The domains of `tauri.security.dangerousRemoteDomainIpcAccess`, with `https` and `http`
+///   unless their scheme is set.
+///
+/// ```rust,ignore
+/// let context = tauri::generate_context!();
+/// let origins = tauri_invoke_http::config_origins(context.config(), !cfg!(feature = "custom-protocol"));
+/// let http = tauri_invoke_http::Invoke::new(origins);
+/// ```
+pub fn config_origins(config: &tauri::Config, dev: bool) -> Vec<String> {
+  let app_url = if dev {
+    &config.build.dev_path
+  } else {
+    &config.build.dist_dir
+  };
+  let mut origins = vec![match app_url {
+    AppUrl::Url(WindowUrl::External(url)) => url.origin().ascii_serialization(),
+    _ => custom_protocol_origin(config),
+  }];
+  for scope in &config.tauri.security.dangerous_remote_domain_ipc_access {
+    let schemes = match &scope.scheme {
+      Some(scheme) => vec![scheme.as_str()],
+      None => vec!["https", "http"],
+    };
+    for scheme in schemes {
+      // parsed, so the origin is serialized like the `Origin` header of the webview
+      let origin = Url::parse(&format!("{}://{}", scheme, scope.domain))
+        .ok()
+        .map(|url| url.origin().ascii_serialization());
+      if let Some(origin) = origin.filter(|origin| origin != "null") {
+        if !origins.contains(&origin) {
+          origins.push(origin);
+        }
+      }
+    }
+  }
+  origins
+}
+
+/// The origin of the bundled frontend, served by the `tauri` custom protocol.
+fn custom_protocol_origin(config: &tauri::Config) -> String {
+  if !cfg!(windows) {
+    "tauri://localhost".into()
+  } else if config.tauri.security.dangerous_use_http_scheme {
+    "http://tauri.localhost".into()
+  } else {
+    "https://tauri.localhost".into()
+  }
+}
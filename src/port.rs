@@ -0,0 +1,159 @@
+// Copyright 2019-2021 Tauri Programme within The Commons Conservancy
+// SPDX-License-Identifier: Apache-2.0
+// SPDX-License-Identifier: MIT
+
+//! The choice of the invoke port.
+
+use std::{fs, io, ops::Range, path::PathBuf, time::Duration};
+
+/// The ports derived from the app identifiers, below the ephemeral ranges of Linux (from
+/// `32768`) and Windows (from `49152`) so outgoing connections don't take them.
+const DERIVED_PORTS: Range<u16> = 10000..32768;
+/// The ports tried after the one an identifier hashes to, when it is taken.
+const DERIVED_ATTEMPTS: u64 = 16;
+
+/// What [`Invoke::start`](crate::Invoke::start) does when the invoke port is taken.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BindPolicy {
+  /// Reports the error to `Invoke::on_server_error` without starting the server.
+  FailFast,
+  /// Tries binding the port `attempts` times in all, waiting `backoff` before the first retry and
+  /// doubling it after each one, then reports the error like [`Self::FailFast`].
+  Retry { attempts: u32, backoff: Duration },
+  /// Listens on a random port instead, reported to `Invoke::on_listening`.
+  RandomPort,
+}
+
+impl Default for BindPolicy {
+  fn default() -> Self {
+    Self::FailFast
+  }
+}
+
+/// The port the server listens on, see `Invoke::on_listening`.
+#[derive(Debug, Clone, Copy)]
+#[non_exhaustive]
+pub struct Listening {
+  pub port: u16,
+  /// The port configured for the server, taken if it differs from `port`.
+  pub requested_port: u16,
+}
+
+impl Listening {
+  /// Whether the [`BindPolicy::RandomPort`] replaced the configured port.
+  pub fn substituted(&self) -> bool {
+    self.port != self.requested_port
+  }
+}
+
+/// Binds the listener of `port` with `bind` following the policy,
+/// returning it with the port it listens on.
+pub(crate) fn bind<T>(
+  port: u16,
+  policy: BindPolicy,
+  bind: impl Fn(u16) -> io::Result<T>,
+) -> io::Result<(T, u16)> {
+  match policy {
+    BindPolicy::FailFast => bind(port).map(|listener| (listener, port)),
+    BindPolicy::Retry {
+      attempts,
+      mut backoff,
+    } => {
+      let mut attempt = 1;
+      loop {
+        match bind(port) {
+          Ok(listener) => return Ok((listener, port)),
+          Err(e) if attempt >= attempts => return Err(e),
+          Err(_) => {
+            std::thread::sleep(backoff);
+            backoff *= 2;
+            attempt += 1;
+          }
+        }
+      }
+    }
+    BindPolicy::RandomPort => bind(port).map(|listener| (listener, port)).or_else(|_| {
+      let port = portpicker::pick_unused_port()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::AddrInUse, "no unused port"))?;
+      bind(port).map(|listener| (listener, port))
+    }),
+  }
+}
+
+/// Where `Invoke::persisted_port` keeps the invoke port across restarts.
+///
+/// ```rust,ignore
+/// struct Settings(AppSettings);
+///
+/// impl tauri_invoke_http::PortStore for Settings {
+///   fn load(&mut self) -> Option<u16> {
+///     self.0.get("invoke_port")
+///   }
+///
+///   fn save(&mut self, port: u16) -> std::io::Result<()> {
+///     self.0.set("invoke_port", port)
+///   }
+/// }
+/// ```
+///
+/// A `PathBuf` stores the port as text in the file at the path.
+pub trait PortStore: Send + 'static {
+  /// The port of the previous launch, if any.
+  fn load(&mut self) -> Option<u16>;
+  /// Keeps the port the server listens on.
+  fn save(&mut self, port: u16) -> io::Result<()>;
+}
+
+impl PortStore for PathBuf {
+  fn load(&mut self) -> Option<u16> {
+    fs::read_to_string(&*self).ok()?.trim().parse().ok()
+  }
+
+  fn save(&mut self, port: u16) -> io::Result<()> {
+    if let Some(dir) = self.parent() {
+      fs::create_dir_all(dir)?;
+    }
+    fs::write(&*self, port.to_string())
+  }
+}
+
+/// The stable port of an app identifier, or the next free one after it.
+///
+/// Returns `None` if they are all taken.
+pub(crate) fn derived(identifier: &str) -> Option<u16> {
+  let span = u64::from(DERIVED_PORTS.end - DERIVED_PORTS.start);
+  let hash = fnv1a(identifier.as_bytes());
+  (0..DERIVED_ATTEMPTS)
+    .map(|attempt| DERIVED_PORTS.start + (hash.wrapping_add(attempt) % span) as u16)
+    .find(|port| portpicker::is_free_tcp(*port))
+}
+
+/// The 64-bit FNV-1a hash, stable across Rust releases unlike the std hashers.
+fn fnv1a(bytes: &[u8]) -> u64 {
+  bytes.iter().fold(0xcbf29ce484222325, |hash, byte| {
+    (hash ^ u64::from(*byte)).wrapping_mul(0x100000001b3)
+  })
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn hashes_with_fnv1a() {
+    assert_eq!(fnv1a(b""), 0xcbf29ce484222325);
+    assert_eq!(fnv1a(b"a"), 0xaf63dc4c8601ec8c);
+    assert_eq!(fnv1a(b"foobar"), 0x85944171f73967e8);
+  }
+
+  #[test]
+  fn derives_stable_ports() {
+    let port = derived("com.tauri.dev").unwrap();
+    assert!(DERIVED_PORTS.contains(&port), "{}", port);
+    // the port the identifier hashes to, or one of the next ones if it is taken
+    let span = u64::from(DERIVED_PORTS.end - DERIVED_PORTS.start);
+    let offset = u64::from(port - DERIVED_PORTS.start);
+    let attempt = (offset + span - fnv1a(b"com.tauri.dev") % span) % span;
+    assert!(attempt < DERIVED_ATTEMPTS, "{}", attempt);
+  }
+}
@@ -0,0 +1,140 @@
+// Copyright 2019-2021 Tauri Programme within The Commons Conservancy
+// SPDX-License-Identifier: Apache-2.0
+// SPDX-License-Identifier: MIT
+
+//! The clients of the requests forwarded by the `Invoke::trusted_proxies`.
+//!
+//! The `X-Forwarded-For` and `X-Forwarded-Proto` headers are only honored on the connections of
+//! a trusted proxy. The client is the last forwarded address that isn't a trusted proxy, as the
+//! ones before it may be sent by the client itself.
+
+use std::net::{IpAddr, SocketAddr};
+
+use tiny_http::Request;
+
+use crate::proxy_protocol::ProxiedClients;
+
+/// The address of the client, with port `0` for the forwarded clients.
+///
+/// The requests relayed by `Invoke::proxy_protocol` come from the source of their preamble.
+pub(crate) fn client(
+  request: &Request,
+  trusted: &[IpAddr],
+  proxied: Option<&ProxiedClients>,
+) -> Option<SocketAddr> {
+  let peer = *request.remote_addr()?;
+  let peer = proxied.and_then(|p| p.get(&peer)).unwrap_or(peer);
+  if !trusted.contains(&peer.ip()) {
+    return Some(peer);
+  }
+  let forwarded: Vec<&str> = request
+    .headers()
+    .iter()
+    .filter(|h| h.field.equiv("X-Forwarded-For"))
+    .flat_map(|h| h.value.as_str().split(','))
+    .collect();
+  // walks back the proxies, stopping at the first invalid address
+  let mut client = peer;
+  for ip in forwarded.into_iter().rev().map_while(parse_address) {
+    client = SocketAddr::new(ip, 0);
+    if !trusted.contains(&ip) {
+      break;
+    }
+  }
+  Some(client)
+}
+
+/// The scheme the client used, `https` behind a proxy terminating TLS.
+pub(crate) fn scheme(
+  request: &Request,
+  trusted: &[IpAddr],
+  proxied: Option<&ProxiedClients>,
+) -> &'static str {
+  let proto = request
+    .remote_addr()
+    .map(|peer| proxied.and_then(|p| p.get(peer)).unwrap_or(*peer))
+    .filter(|peer| trusted.contains(&peer.ip()))
+    .and_then(|_| crate::header(request, "X-Forwarded-Proto"))
+    .and_then(|proto| proto.split(',').next());
+  match proto {
+    Some(proto) if proto.trim().eq_ignore_ascii_case("https") => "https",
+    _ => "http",
+  }
+}
+
+/// Parses an `X-Forwarded-For` address, with or without a port.
+fn parse_address(address: &str) -> Option<IpAddr> {
+  let address = address.trim();
+  address
+    .parse::<IpAddr>()
+    .ok()
+    .or_else(|| address.parse::<SocketAddr>().ok().map(|a| a.ip()))
+}
+
+#[cfg(test)]
+mod tests {
+  use tiny_http::{Header, TestRequest};
+
+  use super::*;
+
+  const PROXY: &str = "10.0.0.1:8080";
+
+  fn request(peer: &str, headers: &[(&str, &str)]) -> Request {
+    let mut request = TestRequest::new().with_remote_addr(peer.parse().unwrap());
+    for (name, value) in headers {
+      request = request.with_header(Header::from_bytes(*name, *value).unwrap());
+    }
+    request.into()
+  }
+
+  fn forwarded_for(peer: &str, values: &[&str]) -> Option<SocketAddr> {
+    let headers: Vec<(&str, &str)> = values.iter().map(|v| ("X-Forwarded-For", *v)).collect();
+    client(&request(peer, &headers), &trusted(), None)
+  }
+
+  fn trusted() -> Vec<IpAddr> {
+    vec!["10.0.0.1".parse().unwrap(), "10.0.0.2".parse().unwrap()]
+  }
+
+  #[test]
+  fn ignores_the_headers_of_untrusted_peers() {
+    assert_eq!(
+      forwarded_for("192.0.2.9:5000", &["203.0.113.7"]),
+      Some("192.0.2.9:5000".parse().unwrap())
+    );
+  }
+
+  #[test]
+  fn walks_back_the_trusted_proxies() {
+    assert_eq!(
+      forwarded_for(PROXY, &["198.51.100.4, 203.0.113.7", "10.0.0.2"]),
+      Some("203.0.113.7:0".parse().unwrap())
+    );
+  }
+
+  #[test]
+  fn reads_addresses_with_ports() {
+    assert_eq!(
+      forwarded_for(PROXY, &["203.0.113.7:5000", "[2001:db8::7]:443"]),
+      Some("[2001:db8::7]:0".parse().unwrap())
+    );
+  }
+
+  #[test]
+  fn stops_at_invalid_addresses() {
+    assert_eq!(
+      forwarded_for(PROXY, &["203.0.113.7, unknown, 10.0.0.2"]),
+      Some("10.0.0.2:0".parse().unwrap())
+    );
+    assert_eq!(forwarded_for(PROXY, &[]), Some(PROXY.parse().unwrap()));
+  }
+
+  #[test]
+  fn reads_the_scheme_behind_trusted_proxies() {
+    let headers = [("X-Forwarded-Proto", "HTTPS, http")];
+    assert_eq!(scheme(&request(PROXY, &headers), &trusted(), None), "https");
+    assert_eq!(scheme(&request(PROXY, &headers), &[], None), "http");
+    let headers = [("X-Forwarded-Proto", "ftp")];
+    assert_eq!(scheme(&request(PROXY, &headers), &trusted(), None), "http");
+  }
+}
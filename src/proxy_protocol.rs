@@ -0,0 +1,269 @@
+// Copyright 2019-2021 Tauri Programme within The Commons Conservancy
+// SPDX-License-Identifier: Apache-2.0
+// SPDX-License-Identifier: MIT
+
+//! The [PROXY protocol](https://www.haproxy.org/download/2.9/doc/proxy-protocol.txt) v1 and v2
+//! of `Invoke::proxy_protocol`.
+//!
+//! `tiny_http` reads the connections itself, so the preamble is read by a relay listening on the
+//! invoke port, which forwards the rest of the connection to the server on an internal port.
+//! The client of each relayed connection is kept by the local address of the relay, which the
+//! server sees as the remote address of the requests. The other connections to the internal port
+//! are refused, as they would bypass the relay.
+
+use std::{
+  collections::HashMap,
+  io::{self, Read},
+  net::{IpAddr, Ipv4Addr, Ipv6Addr, Shutdown, SocketAddr, TcpListener, TcpStream},
+  sync::{Arc, Mutex},
+  time::Duration,
+};
+
+use crate::trace::log_error;
+
+const V2_SIGNATURE: &[u8; 12] = b"\r\n\r\n\0\r\nQUIT\n";
+/// The longest v1 preamble, including its `\r\n`.
+const V1_MAX_LENGTH: usize = 107;
+/// The time given to the load balancer to send the preamble.
+const PREAMBLE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// The clients of the relayed connections, by local address of the relay.
+#[derive(Default)]
+pub(crate) struct ProxiedClients(Mutex<HashMap<SocketAddr, SocketAddr>>);
+
+impl ProxiedClients {
+  /// The client of a connection from the relay.
+  pub(crate) fn get(&self, peer: &SocketAddr) -> Option<SocketAddr> {
+    self.0.lock().unwrap().get(peer).copied()
+  }
+}
+
+/// Relays the connections of the invoke port to the server on `127.0.0.1:{internal_port}`,
+/// closing the connections of the peers other than the `load_balancers`.
+pub(crate) fn start(
+  listener: TcpListener,
+  internal_port: u16,
+  clients: Arc<ProxiedClients>,
+  load_balancers: Vec<IpAddr>,
+) {
+  let load_balancers = Arc::new(load_balancers);
+  std::thread::spawn(move || {
+    for stream in listener.incoming() {
+      let stream = match stream {
+        Ok(stream) => stream,
+        Err(_) => continue,
+      };
+      let (clients, load_balancers) = (clients.clone(), load_balancers.clone());
+      std::thread::spawn(move || {
+        if let Err(e) = relay(stream, internal_port, &clients, &load_balancers) {
+          log_error!("failed to relay a PROXY protocol connection: {}", e);
+        }
+      });
+    }
+  });
+}
+
+fn relay(
+  mut client: TcpStream,
+  internal_port: u16,
+  clients: &ProxiedClients,
+  load_balancers: &[IpAddr],
+) -> io::Result<()> {
+  let peer = client.peer_addr()?;
+  // any other peer could claim to relay any client
+  if !load_balancers.contains(&peer.ip()) {
+    return Err(io::Error::new(
+      io::ErrorKind::PermissionDenied,
+      format!("{} is not a trusted load balancer", peer.ip()),
+    ));
+  }
+  client.set_read_timeout(Some(PREAMBLE_TIMEOUT))?;
+  let source = match read_preamble(&mut client)? {
+    Some(source) => source,
+    // `LOCAL` and `UNKNOWN` connections, such as health checks, come from the load balancer
+    None => peer,
+  };
+  client.set_read_timeout(None)?;
+
+  let server = TcpStream::connect(("127.0.0.1", internal_port))?;
+  let local = server.local_addr()?;
+  // registered before any byte is forwarded, so the server never reads a request without it
+  clients.0.lock().unwrap().insert(local, source);
+
+  let (mut client_reader, mut server_writer) = (client.try_clone()?, server.try_clone()?);
+  let upload = std::thread::spawn(move || {
+    let _ = io::copy(&mut client_reader, &mut server_writer);
+    let _ = server_writer.shutdown(Shutdown::Write);
+  });
+  let (mut server_reader, mut client_writer) = (server, client);
+  let _ = io::copy(&mut server_reader, &mut client_writer);
+  let _ = client_writer.shutdown(Shutdown::Both);
+  let _ = upload.join();
+  clients.0.lock().unwrap().remove(&local);
+  Ok(())
+}
+
+fn invalid(message: &str) -> io::Error {
+  io::Error::new(io::ErrorKind::InvalidData, message.to_string())
+}
+
+/// Reads the v1 or v2 preamble, returning the source address of proxied connections.
+fn read_preamble(stream: &mut impl Read) -> io::Result<Option<SocketAddr>> {
+  let mut start = [0u8; 12];
+  stream.read_exact(&mut start)?;
+  if &start == V2_SIGNATURE {
+    read_v2(stream)
+  } else if start.starts_with(b"PROXY ") {
+    read_v1(stream, &start)
+  } else {
+    Err(invalid("the connection has no PROXY protocol preamble"))
+  }
+}
+
+/// Reads the rest of a `PROXY TCP4 {source} {destination} {source port} {destination port}\r\n` line.
+fn read_v1(stream: &mut impl Read, start: &[u8]) -> io::Result<Option<SocketAddr>> {
+  let mut line = start.to_vec();
+  // read byte by byte, so the request after the line stays on the socket
+  while !line.ends_with(b"\r\n") {
+    if line.len() == V1_MAX_LENGTH {
+      return Err(invalid("the PROXY protocol v1 line is too long"));
+    }
+    let mut byte = [0u8];
+    stream.read_exact(&mut byte)?;
+    line.push(byte[0]);
+  }
+  let line = std::str::from_utf8(&line[..line.len() - 2])
+    .map_err(|_| invalid("the PROXY protocol v1 line is not ASCII"))?;
+  let fields: Vec<&str> = line.split(' ').collect();
+  match fields.as_slice() {
+    ["PROXY", "UNKNOWN", ..] => Ok(None),
+    ["PROXY", "TCP4" | "TCP6", source, _, source_port, _] => {
+      let ip = source
+        .parse::<IpAddr>()
+        .map_err(|_| invalid("invalid PROXY protocol v1 source address"))?;
+      let port = source_port
+        .parse::<u16>()
+        .map_err(|_| invalid("invalid PROXY protocol v1 source port"))?;
+      Ok(Some(SocketAddr::new(ip, port)))
+    }
+    _ => Err(invalid("invalid PROXY protocol v1 line")),
+  }
+}
+
+/// Reads the rest of a binary v2 header, after its signature.
+fn read_v2(stream: &mut impl Read) -> io::Result<Option<SocketAddr>> {
+  let mut header = [0u8; 4];
+  stream.read_exact(&mut header)?;
+  let (version_command, family) = (header[0], header[1]);
+  let length = u16::from_be_bytes([header[2], header[3]]) as usize;
+  if version_command >> 4 != 2 {
+    return Err(invalid("unsupported PROXY protocol version"));
+  }
+  let mut addresses = vec![0u8; length];
+  stream.read_exact(&mut addresses)?;
+  // `LOCAL` connections come from the load balancer itself
+  if version_command & 0x0f == 0 {
+    return Ok(None);
+  }
+  let port = |at: usize| u16::from_be_bytes([addresses[at], addresses[at + 1]]);
+  match family >> 4 {
+    // the TLVs after the addresses are ignored
+    1 if length >= 12 => {
+      let ip = Ipv4Addr::new(addresses[0], addresses[1], addresses[2], addresses[3]);
+      Ok(Some(SocketAddr::new(ip.into(), port(8))))
+    }
+    2 if length >= 36 => {
+      let mut octets = [0u8; 16];
+      octets.copy_from_slice(&addresses[..16]);
+      Ok(Some(SocketAddr::new(
+        Ipv6Addr::from(octets).into(),
+        port(32),
+      )))
+    }
+    _ => Ok(None),
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn preamble(bytes: &[u8]) -> io::Result<Option<SocketAddr>> {
+    read_preamble(&mut &bytes[..])
+  }
+
+  #[test]
+  fn reads_v1_lines() {
+    assert_eq!(
+      preamble(b"PROXY TCP4 203.0.113.7 192.0.2.1 51234 443\r\nGET /").unwrap(),
+      Some("203.0.113.7:51234".parse().unwrap())
+    );
+    assert_eq!(
+      preamble(b"PROXY TCP6 2001:db8::7 2001:db8::1 51234 443\r\n").unwrap(),
+      Some("[2001:db8::7]:51234".parse().unwrap())
+    );
+    assert_eq!(preamble(b"PROXY UNKNOWN\r\n").unwrap(), None);
+  }
+
+  #[test]
+  fn leaves_the_request_after_a_v1_line() {
+    let mut stream = &b"PROXY UNKNOWN\r\nGET / HTTP/1.1\r\n"[..];
+    read_preamble(&mut stream).unwrap();
+    assert_eq!(stream, b"GET / HTTP/1.1\r\n");
+  }
+
+  #[test]
+  fn rejects_invalid_v1_lines() {
+    assert!(preamble(b"PROXY TCP4 203.0.113.7 192.0.2.1 port 443\r\n").is_err());
+    assert!(preamble(b"PROXY TCP4 not-an-ip 192.0.2.1 51234 443\r\n").is_err());
+    assert!(preamble(b"PROXY TCP4 203.0.113.7\r\n").is_err());
+    let long = format!("PROXY TCP4 {}\r\n", "1".repeat(V1_MAX_LENGTH));
+    assert!(preamble(long.as_bytes()).is_err());
+  }
+
+  #[test]
+  fn rejects_connections_without_preamble() {
+    assert!(preamble(b"GET / HTTP/1.1\r\nHost: localhost\r\n").is_err());
+  }
+
+  fn v2(command: u8, family: u8, addresses: &[u8]) -> Vec<u8> {
+    let mut header = V2_SIGNATURE.to_vec();
+    header.extend([0x20 | command, family]);
+    header.extend((addresses.len() as u16).to_be_bytes());
+    header.extend(addresses);
+    header
+  }
+
+  #[test]
+  fn reads_v2_headers() {
+    let ipv4 = [203, 0, 113, 7, 192, 0, 2, 1, 0xc8, 0x22, 0x01, 0xbb];
+    assert_eq!(
+      preamble(&v2(1, 0x11, &ipv4)).unwrap(),
+      Some("203.0.113.7:51234".parse().unwrap())
+    );
+
+    let mut ipv6 = vec![0u8; 36];
+    ipv6[..16].copy_from_slice(&"2001:db8::7".parse::<Ipv6Addr>().unwrap().octets());
+    ipv6[32..34].copy_from_slice(&51234u16.to_be_bytes());
+    assert_eq!(
+      preamble(&v2(1, 0x21, &ipv6)).unwrap(),
+      Some("[2001:db8::7]:51234".parse().unwrap())
+    );
+  }
+
+  #[test]
+  fn reads_v2_local_connections() {
+    assert_eq!(preamble(&v2(0, 0x00, &[])).unwrap(), None);
+    assert_eq!(preamble(&v2(0, 0x11, &[0; 12])).unwrap(), None);
+  }
+
+  #[test]
+  fn rejects_invalid_v2_headers() {
+    let mut header = v2(1, 0x11, &[0; 12]);
+    header[12] = 0x11;
+    assert!(preamble(&header).is_err());
+    // the addresses are shorter than their length
+    let header = v2(1, 0x11, &[0; 12]);
+    assert!(preamble(&header[..header.len() - 1]).is_err());
+  }
+}
@@ -0,0 +1,61 @@
+// Copyright 2019-2021 Tauri Programme within The Commons Conservancy
+// SPDX-License-Identifier: Apache-2.0
+// SPDX-License-Identifier: MIT
+
+//! The config file of `Invoke::watch_config`, reloaded when it changes.
+//!
+//! The file is polled for a new modification time, as the crate has no file system
+//! notification dependency.
+
+use std::{
+  fs,
+  path::{Path, PathBuf},
+  time::{Duration, SystemTime},
+};
+
+use serde::Deserialize;
+
+use crate::{origins::AllowedOrigins, trace::log_error};
+
+const POLL_INTERVAL: Duration = Duration::from_secs(2);
+
+/// The settings of the file, each one left unchanged when missing.
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+struct ConfigFile {
+  allowed_origins: Option<Vec<String>>,
+}
+
+/// Applies the file, then reapplies it on a background thread whenever it changes.
+pub(crate) fn watch(path: PathBuf, origins: AllowedOrigins) {
+  let mut modified = check(&path, &origins, None);
+  std::thread::spawn(move || loop {
+    std::thread::sleep(POLL_INTERVAL);
+    modified = check(&path, &origins, modified);
+  });
+}
+
+/// Reloads the file if it was modified since `modified`, returning its modification time.
+fn check(
+  path: &Path,
+  origins: &AllowedOrigins,
+  modified: Option<SystemTime>,
+) -> Option<SystemTime> {
+  let current = fs::metadata(path).and_then(|m| m.modified()).ok();
+  if current.is_some() && current != modified {
+    // a file being written fails to parse, and is reloaded once its last write changes it
+    if let Err(e) = reload(path, origins) {
+      log_error!("failed to reload the config file {}: {}", path.display(), e);
+    }
+  }
+  current
+}
+
+fn reload(path: &Path, origins: &AllowedOrigins) -> Result<(), String> {
+  let bytes = fs::read(path).map_err(|e| e.to_string())?;
+  let file: ConfigFile = serde_json::from_slice(&bytes).map_err(|e| e.to_string())?;
+  if let Some(allowed_origins) = file.allowed_origins {
+    origins.set(allowed_origins);
+  }
+  Ok(())
+}
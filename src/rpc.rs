@@ -0,0 +1,146 @@
+// Copyright 2019-2021 Tauri Programme within The Commons Conservancy
+// SPDX-License-Identifier: Apache-2.0
+// SPDX-License-Identifier: MIT
+
+//! A [JSON-RPC 2.0](https://www.jsonrpc.org/specification) endpoint on `POST /rpc`.
+//!
+//! The `method` of a call is `{window}.{cmd}` and its `params` object holds the command arguments.
+//! Batches of calls are dispatched like the items of a [batch](crate::batch).
+
+use std::sync::{atomic::AtomicUsize, Arc};
+
+use serde_json::{json, Map, Value as JsonValue};
+use tauri::{AppHandle, Runtime};
+use tiny_http::{Method, Request};
+
+use crate::{
+  batch::{dispatch, item_payload, respond_error, Batch, BatchItems, Protocol},
+  body_error, body_reader,
+  dispatch::Dispatcher,
+  invoke_window, merge_header_args, query_args, request_url, respond_value, Config, ErrorCode,
+  HttpError,
+};
+
+const PARSE_ERROR: i64 = -32700;
+const INVALID_REQUEST: i64 = -32600;
+const METHOD_NOT_FOUND: i64 = -32601;
+const INVALID_PARAMS: i64 = -32602;
+
+fn error_object(code: i64, message: &str, id: JsonValue) -> JsonValue {
+  json!({
+    "jsonrpc": "2.0",
+    "error": { "code": code, "message": message },
+    "id": id,
+  })
+}
+
+/// A valid call, with its `id` unset for notifications.
+struct Call {
+  id: Option<JsonValue>,
+  method: String,
+  params: Map<String, JsonValue>,
+}
+
+/// Reads a call, returning the error code and message of invalid ones.
+fn parse_call(call: JsonValue) -> Result<Call, (i64, &'static str)> {
+  let mut call = match call {
+    JsonValue::Object(call) => call,
+    _ => return Err((INVALID_REQUEST, "Invalid Request")),
+  };
+  if call.get("jsonrpc").and_then(|v| v.as_str()) != Some("2.0") {
+    return Err((INVALID_REQUEST, "Invalid Request"));
+  }
+  let method = match call.remove("method") {
+    Some(JsonValue::String(method)) => method,
+    _ => return Err((INVALID_REQUEST, "Invalid Request")),
+  };
+  let params = match call.remove("params") {
+    None | Some(JsonValue::Null) => Map::new(),
+    Some(JsonValue::Object(params)) => params,
+    // commands take named arguments
+    Some(_) => return Err((INVALID_PARAMS, "Invalid params: expected an object")),
+  };
+  Ok(Call {
+    id: call.remove("id"),
+    method,
+    params,
+  })
+}
+
+/// Reads the calls of the request and dispatches them.
+pub(crate) fn handle<R: Runtime>(
+  app: &AppHandle<R>,
+  mut request: Request,
+  config: &Arc<Config>,
+  next_id: &AtomicUsize,
+  pending: &Arc<BatchItems>,
+  dispatcher: &Arc<Dispatcher>,
+) {
+  if request.method() != &Method::Post {
+    return respond_error(
+      request,
+      HttpError::method_not_allowed("POST, OPTIONS"),
+      config,
+    );
+  }
+  let invoke_key = match request_url(&request)
+    .and_then(|url| query_args(&url).map_err(|e| HttpError::new(400, ErrorCode::InvalidArgs, e)))
+  {
+    Ok((_, invoke_key)) => invoke_key,
+    Err(e) => return respond_error(request, e, config),
+  };
+
+  let body = body_reader(&mut request, config).map(serde_json::from_reader::<_, JsonValue>);
+  let (single, calls) = match body {
+    Ok(Ok(JsonValue::Array(calls))) if calls.is_empty() => {
+      let error = error_object(INVALID_REQUEST, "Invalid Request", JsonValue::Null);
+      return respond_value(request, "", Ok(error), Vec::new(), config);
+    }
+    Ok(Ok(JsonValue::Array(calls))) => (false, calls),
+    Ok(Ok(call)) => (true, vec![call]),
+    Ok(Err(e)) if e.is_io() => return respond_error(request, body_error(e.into()), config),
+    Ok(Err(_)) => {
+      let error = error_object(PARSE_ERROR, "Parse error", JsonValue::Null);
+      return respond_value(request, "", Ok(error), Vec::new(), config);
+    }
+    Err(e) => return respond_error(request, e, config),
+  };
+
+  let mut ids = Vec::with_capacity(calls.len());
+  let mut failures = Vec::new();
+  let mut payloads = Vec::new();
+  for (index, call) in calls.into_iter().enumerate() {
+    let fallback_id = call.get("id").cloned().unwrap_or_default();
+    let call = match parse_call(call) {
+      Ok(call) => call,
+      Err((code, message)) => {
+        // invalid calls are always answered
+        ids.push(Some(fallback_id));
+        failures.push((index, code, message));
+        continue;
+      }
+    };
+    ids.push(call.id);
+    let (window_label, cmd) = call.method.split_once('.').unwrap_or_default();
+    let window = match invoke_window(app, window_label, config) {
+      Some(window) => window,
+      None => {
+        failures.push((index, METHOD_NOT_FOUND, "Method not found"));
+        continue;
+      }
+    };
+    match item_payload(cmd.into(), call.params, invoke_key.clone()) {
+      Ok(mut payload) => {
+        merge_header_args(&mut payload, request.headers(), config);
+        payloads.push((index, window, payload));
+      }
+      Err(_) => failures.push((index, INVALID_PARAMS, "Invalid params")),
+    }
+  }
+
+  let batch = Arc::new(Batch::new(request, Protocol::JsonRpc { single }, ids));
+  for (index, code, message) in failures {
+    batch.fail(index, code, message, config);
+  }
+  dispatch(&batch, payloads, config, next_id, pending, dispatcher);
+}
@@ -0,0 +1,104 @@
+// Copyright 2019-2021 Tauri Programme within The Commons Conservancy
+// SPDX-License-Identifier: Apache-2.0
+// SPDX-License-Identifier: MIT
+
+//! The initialization script served as `GET /__client.js`, for the apps whose CSP refuses
+//! inline scripts, with its subresource integrity hash.
+
+use base64::Engine;
+use tiny_http::{Header, Method, Request, Response};
+
+use crate::{batch, cors, Config, HttpError};
+
+pub(crate) const PATH: &str = "/__client.js";
+
+/// The `integrity` attribute of the script, such as `sha256-...`.
+pub(crate) fn integrity(script: &str) -> String {
+  format!(
+    "sha256-{}",
+    base64::engine::general_purpose::STANDARD.encode(sha256(script.as_bytes()))
+  )
+}
+
+/// Answers `GET /__client.js` with the script.
+pub(crate) fn handle(request: Request, script: &str, config: &Config) {
+  if !matches!(request.method(), Method::Get | Method::Head) {
+    let error = HttpError::method_not_allowed("GET, HEAD, OPTIONS");
+    return batch::respond_error(request, error, config);
+  }
+  let mut r = Response::from_data(script.as_bytes())
+    .with_header(Header::from_bytes("Content-Type", "text/javascript; charset=utf-8").unwrap())
+    .with_header(Header::from_bytes("Cache-Control", "no-cache").unwrap());
+  // scripts with an `integrity` attribute are fetched with CORS
+  cors(&request, &mut r, &config.allowed_origins);
+  let _ = request.respond(r);
+}
+
+const K: [u32; 64] = [
+  0x428a2f98, 0x71374491, 0xb5c0fbcf, 0xe9b5dba5, 0x3956c25b, 0x59f111f1, 0x923f82a4, 0xab1c5ed5,
+  0xd807aa98, 0x12835b01, 0x243185be, 0x550c7dc3, 0x72be5d74, 0x80deb1fe, 0x9bdc06a7, 0xc19bf174,
+  0xe49b69c1, 0xefbe4786, 0x0fc19dc6, 0x240ca1cc, 0x2de92c6f, 0x4a7484aa, 0x5cb0a9dc, 0x76f988da,
+  0x983e5152, 0xa831c66d, 0xb00327c8, 0xbf597fc7, 0xc6e00bf3, 0xd5a79147, 0x06ca6351, 0x14292967,
+  0x27b70a85, 0x2e1b2138, 0x4d2c6dfc, 0x53380d13, 0x650a7354, 0x766a0abb, 0x81c2c92e, 0x92722c85,
+  0xa2bfe8a1, 0xa81a664b, 0xc24b8b70, 0xc76c51a3, 0xd192e819, 0xd6990624, 0xf40e3585, 0x106aa070,
+  0x19a4c116, 0x1e376c08, 0x2748774c, 0x34b0bcb5, 0x391c0cb3, 0x4ed8aa4a, 0x5b9cca4f, 0x682e6ff3,
+  0x748f82ee, 0x78a5636f, 0x84c87814, 0x8cc70208, 0x90befffa, 0xa4506ceb, 0xbef9a3f7, 0xc67178f2,
+];
+
+/// SHA-256, only used to compute the integrity hash of the script.
+fn sha256(data: &[u8]) -> [u8; 32] {
+  let mut h: [u32; 8] = [
+    0x6a09e667, 0xbb67ae85, 0x3c6ef372, 0xa54ff53a, 0x510e527f, 0x9b05688c, 0x1f83d9ab, 0x5be0cd19,
+  ];
+  let mut message = data.to_vec();
+  message.push(0x80);
+  while message.len() % 64 != 56 {
+    message.push(0);
+  }
+  message.extend_from_slice(&(data.len() as u64 * 8).to_be_bytes());
+
+  for chunk in message.chunks(64) {
+    let mut w = [0u32; 64];
+    for (i, word) in chunk.chunks(4).enumerate() {
+      w[i] = u32::from_be_bytes([word[0], word[1], word[2], word[3]]);
+    }
+    for i in 16..64 {
+      let s0 = w[i - 15].rotate_right(7) ^ w[i - 15].rotate_right(18) ^ (w[i - 15] >> 3);
+      let s1 = w[i - 2].rotate_right(17) ^ w[i - 2].rotate_right(19) ^ (w[i - 2] >> 10);
+      w[i] = w[i - 16]
+        .wrapping_add(s0)
+        .wrapping_add(w[i - 7])
+        .wrapping_add(s1);
+    }
+    let [mut a, mut b, mut c, mut d, mut e, mut f, mut g, mut hh] = h;
+    for (k, word) in K.iter().zip(w) {
+      let s1 = e.rotate_right(6) ^ e.rotate_right(11) ^ e.rotate_right(25);
+      let ch = (e & f) ^ (!e & g);
+      let t1 = hh
+        .wrapping_add(s1)
+        .wrapping_add(ch)
+        .wrapping_add(*k)
+        .wrapping_add(word);
+      let s0 = a.rotate_right(2) ^ a.rotate_right(13) ^ a.rotate_right(22);
+      let maj = (a & b) ^ (a & c) ^ (b & c);
+      let t2 = s0.wrapping_add(maj);
+      hh = g;
+      g = f;
+      f = e;
+      e = d.wrapping_add(t1);
+      d = c;
+      c = b;
+      b = a;
+      a = t1.wrapping_add(t2);
+    }
+    for (h, v) in h.iter_mut().zip([a, b, c, d, e, f, g, hh]) {
+      *h = h.wrapping_add(v);
+    }
+  }
+
+  let mut digest = [0; 32];
+  for (bytes, h) in digest.chunks_mut(4).zip(h) {
+    bytes.copy_from_slice(&h.to_be_bytes());
+  }
+  digest
+}
@@ -0,0 +1,224 @@
+// Copyright 2019-2021 Tauri Programme within The Commons Conservancy
+// SPDX-License-Identifier: Apache-2.0
+// SPDX-License-Identifier: MIT
+
+//! Responses streamed to the client as their parts become available.
+
+use std::{
+  collections::VecDeque,
+  io::Write,
+  sync::{Arc, Condvar, Mutex},
+  time::Duration,
+};
+
+use tiny_http::{Header, Request, StatusCode};
+
+use crate::{Backpressure, ChannelState};
+
+/// How long a stream with a heartbeat stays idle before sending it.
+const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(5);
+
+/// A chunked response body, flushed to the client on every write.
+///
+/// `tiny_http` buffers streamed bodies, so the response is written on the raw connection instead.
+pub(crate) struct ChunkedWriter {
+  writer: Box<dyn Write + Send>,
+}
+
+impl ChunkedWriter {
+  /// Sends the status line and headers of the response.
+  pub(crate) fn start(request: Request, status: u16, headers: &[Header]) -> std::io::Result<Self> {
+    let mut writer = request.into_writer();
+    write!(
+      writer,
+      "HTTP/1.1 {} {}\r\n",
+      status,
+      StatusCode(status).default_reason_phrase()
+    )?;
+    for header in headers {
+      write!(writer, "{}: {}\r\n", header.field, header.value)?;
+    }
+    writer.write_all(b"Transfer-Encoding: chunked\r\n\r\n")?;
+    writer.flush()?;
+    Ok(Self { writer })
+  }
+
+  /// Sends a part of the body.
+  pub(crate) fn send(&mut self, data: &[u8]) -> std::io::Result<()> {
+    if data.is_empty() {
+      // an empty chunk ends the body
+      return Ok(());
+    }
+    write!(self.writer, "{:x}\r\n", data.len())?;
+    self.writer.write_all(data)?;
+    self.writer.write_all(b"\r\n")?;
+    self.writer.flush()
+  }
+}
+
+impl Drop for ChunkedWriter {
+  fn drop(&mut self) {
+    let _ = self.writer.write_all(b"0\r\n\r\n");
+    let _ = self.writer.flush();
+  }
+}
+
+#[derive(Default)]
+struct Buffer {
+  parts: VecDeque<Vec<u8>>,
+  /// No more parts are accepted, as the client is gone or didn't keep up.
+  closed: bool,
+  /// The buffer overflowed with the [`Backpressure::Close`] policy.
+  overflowed: bool,
+  /// The last part was sent.
+  ended: bool,
+}
+
+#[derive(Default)]
+struct Shared {
+  buffer: Mutex<Buffer>,
+  /// Signals both new parts and freed space.
+  changed: Condvar,
+}
+
+/// A chunked response written on a background thread, buffering up to `capacity` parts
+/// for slow clients and applying the backpressure policy beyond.
+///
+/// Dropping the writer ends the response once the buffered parts are sent.
+/// The parts of other connections, such as WebSocket messages, are written by [`Self::spawn_with`].
+pub(crate) struct BufferedWriter {
+  shared: Arc<Shared>,
+  capacity: usize,
+  policy: Backpressure,
+}
+
+impl BufferedWriter {
+  /// Starts sending the parts, ending the response with `overflow` if the buffer overflows.
+  ///
+  /// The `heartbeat` part is sent when the stream is idle, so a gone client is noticed.
+  pub(crate) fn spawn(
+    mut writer: ChunkedWriter,
+    capacity: usize,
+    policy: Backpressure,
+    overflow: Option<Vec<u8>>,
+    heartbeat: Option<Vec<u8>>,
+  ) -> Self {
+    Self::spawn_with(
+      move |part| writer.send(part),
+      capacity,
+      policy,
+      overflow,
+      heartbeat,
+    )
+  }
+
+  /// Starts sending the parts with `send`, dropped once the buffered parts are sent.
+  pub(crate) fn spawn_with(
+    mut send: impl FnMut(&[u8]) -> std::io::Result<()> + Send + 'static,
+    capacity: usize,
+    policy: Backpressure,
+    overflow: Option<Vec<u8>>,
+    heartbeat: Option<Vec<u8>>,
+  ) -> Self {
+    let shared = Arc::new(Shared::default());
+    let sender = shared.clone();
+    std::thread::spawn(move || loop {
+      let mut buffer = sender.buffer.lock().unwrap();
+      while buffer.parts.is_empty() && !buffer.ended && !buffer.closed {
+        if heartbeat.is_none() {
+          buffer = sender.changed.wait(buffer).unwrap();
+          continue;
+        }
+        let (waited, timeout) = sender
+          .changed
+          .wait_timeout(buffer, HEARTBEAT_INTERVAL)
+          .unwrap();
+        buffer = waited;
+        if timeout.timed_out() {
+          break;
+        }
+      }
+      if buffer.closed {
+        if buffer.overflowed {
+          if let Some(overflow) = &overflow {
+            let _ = send(overflow);
+          }
+        }
+        break;
+      }
+      let part = match buffer.parts.pop_front() {
+        Some(part) => part,
+        None if buffer.ended => break,
+        None => heartbeat.clone().unwrap_or_default(),
+      };
+      drop(buffer);
+      sender.changed.notify_all();
+      if send(&part).is_err() {
+        sender.buffer.lock().unwrap().closed = true;
+        sender.changed.notify_all();
+        break;
+      }
+    });
+    Self {
+      shared,
+      capacity: capacity.max(1),
+      policy,
+    }
+  }
+
+  /// Buffers a part, returning the state of the stream.
+  pub(crate) fn send(&self, part: Vec<u8>) -> ChannelState {
+    let mut buffer = self.shared.buffer.lock().unwrap();
+    let mut state = ChannelState::Open;
+    if buffer.parts.len() >= self.capacity && !buffer.closed {
+      match self.policy {
+        Backpressure::Block => {
+          while buffer.parts.len() >= self.capacity && !buffer.closed {
+            buffer = self.shared.changed.wait(buffer).unwrap();
+          }
+        }
+        Backpressure::DropOldest => {
+          buffer.parts.pop_front();
+          state = ChannelState::Lagging;
+        }
+        Backpressure::Close => {
+          buffer.closed = true;
+          buffer.overflowed = true;
+          buffer.parts.clear();
+        }
+      }
+    }
+    if buffer.closed || buffer.ended {
+      drop(buffer);
+      self.shared.changed.notify_all();
+      return ChannelState::Closed;
+    }
+    buffer.parts.push_back(part);
+    drop(buffer);
+    self.shared.changed.notify_all();
+    state
+  }
+
+  /// Whether the client is gone or didn't keep up.
+  pub(crate) fn is_closed(&self) -> bool {
+    self.shared.buffer.lock().unwrap().closed
+  }
+
+  /// Ends the response with a last part, sent even if the buffer is full.
+  pub(crate) fn end(&self, part: Vec<u8>) {
+    let mut buffer = self.shared.buffer.lock().unwrap();
+    if !buffer.ended {
+      buffer.parts.push_back(part);
+      buffer.ended = true;
+    }
+    drop(buffer);
+    self.shared.changed.notify_all();
+  }
+}
+
+impl Drop for BufferedWriter {
+  fn drop(&mut self) {
+    self.shared.buffer.lock().unwrap().ended = true;
+    self.shared.changed.notify_all();
+  }
+}
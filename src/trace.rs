@@ -0,0 +1,510 @@
+// Copyright 2019-2021 Tauri Programme within The Commons Conservancy
+// SPDX-License-Identifier: Apache-2.0
+// SPDX-License-Identifier: MIT
+
+//! The traces of the requests: their id, their `tracing` span, with the `tracing` feature,
+//! and their record passed to the `Invoke::request_logger` and the metrics.
+//!
+//! The id of a request is its `X-Request-Id` header, or a random one if missing or invalid,
+//! and is sent back in the `X-Request-Id` header of the response.
+//!
+//! Requests sent with a [W3C trace context](https://www.w3.org/TR/trace-context/) get a span id
+//! of their own, the commands mapping the `traceparent` header receiving it as their parent.
+//!
+//! With `Invoke::server_timing`, the responses have a `Server-Timing` header with the time taken
+//! to read the invoke, to wait for its dispatch and to run its command.
+//!
+//! A request trace lasts from the accept of the request to its response, which happens on the
+//! responder thread for the invokes waiting for their command. The response functions record
+//! their status in the trace entered on the current thread.
+
+use std::{
+  cell::RefCell,
+  collections::hash_map::RandomState,
+  hash::{BuildHasher, Hasher},
+  marker::PhantomData,
+  net::{IpAddr, SocketAddr},
+  sync::{Arc, Mutex},
+  time::{Duration, Instant},
+};
+
+use serde_json::Value as JsonValue;
+use tiny_http::{Header, Request};
+
+use crate::{inspector::Inspector, metrics::Metrics, proxy, proxy_protocol::ProxiedClients};
+
+/// The header carrying the id of a request.
+pub(crate) const REQUEST_ID_HEADER: &str = "X-Request-Id";
+/// The longest `X-Request-Id` accepted from clients.
+const MAX_REQUEST_ID_LENGTH: usize = 128;
+const TRACEPARENT_HEADER: &str = "traceparent";
+const TRACESTATE_HEADER: &str = "tracestate";
+
+/// Logs an error with `tracing`, or to stderr without the `tracing` feature.
+macro_rules! log_error {
+  ($($arg:tt)*) => {{
+    #[cfg(feature = "tracing")]
+    tracing::error!($($arg)*);
+    #[cfg(not(feature = "tracing"))]
+    eprintln!($($arg)*);
+  }};
+}
+pub(crate) use log_error;
+
+/// Called with the record of each completed request, see `Invoke::request_logger`.
+pub(crate) type RequestLogger = Arc<dyn Fn(&RequestRecord) + Send + Sync>;
+
+/// What the traces report the requests to, set up once the server starts.
+#[derive(Clone, Default)]
+pub(crate) struct Observers {
+  pub(crate) loggers: Vec<RequestLogger>,
+  pub(crate) metrics: Option<Arc<Metrics>>,
+  pub(crate) inspector: Option<Arc<Inspector>>,
+  pub(crate) server_timing: bool,
+  pub(crate) trusted_proxies: Vec<IpAddr>,
+  /// The clients of the connections relayed by `Invoke::proxy_protocol`.
+  pub(crate) proxied: Option<Arc<ProxiedClients>>,
+}
+
+/// A completed request, passed to the `Invoke::request_logger` and written to the `Invoke::access_log`.
+#[derive(Debug, Clone)]
+#[non_exhaustive]
+pub struct RequestRecord {
+  /// The `X-Request-Id` of the request, or the generated one.
+  pub request_id: String,
+  pub method: String,
+  /// The path and query string of the request.
+  pub path: String,
+  /// The HTTP version of the request, such as `1.1`.
+  pub http_version: String,
+  /// The window of the invoke, if the request invoked a command.
+  pub window: Option<String>,
+  /// The invoked command, if any.
+  pub cmd: Option<String>,
+  /// The response status, unknown for streamed responses.
+  pub status: Option<u16>,
+  /// The size of the response body, unknown for streamed responses.
+  pub bytes: Option<u64>,
+  /// The size of the request body, unknown without a `Content-Length`.
+  pub request_bytes: Option<u64>,
+  /// The time from the accept of the request to its response.
+  pub duration: Duration,
+  /// The address of the client, forwarded by a trusted proxy with port `0`.
+  pub client: Option<SocketAddr>,
+  /// `https` if a trusted proxy terminated TLS, otherwise `http`.
+  pub scheme: &'static str,
+  pub origin: Option<String>,
+  /// The trace id of the `traceparent` header, if any.
+  pub trace_id: Option<String>,
+  /// The span id of the request in the trace, if any.
+  pub span_id: Option<String>,
+  /// The span id of the `traceparent` header, if any.
+  pub parent_span_id: Option<String>,
+}
+
+/// The W3C trace context of a request, as a child of the `traceparent` of the client.
+struct TraceContext {
+  trace_id: String,
+  parent_id: String,
+  span_id: String,
+  flags: String,
+  state: Option<String>,
+}
+
+impl TraceContext {
+  /// Parses the `traceparent` and `tracestate` headers, ignoring invalid ones.
+  fn from_request(request: &Request) -> Option<Self> {
+    let traceparent = crate::header(request, TRACEPARENT_HEADER)?.trim();
+    let mut parts = traceparent.split('-');
+    let (version, trace_id, parent_id, flags) =
+      (parts.next()?, parts.next()?, parts.next()?, parts.next()?);
+    // later versions may add fields after the flags
+    let valid = is_hex(version, 2)
+      && version != "ff"
+      && (version != "00" || parts.next().is_none())
+      && is_hex(trace_id, 32)
+      && is_hex(parent_id, 16)
+      && is_hex(flags, 2)
+      && trace_id.bytes().any(|b| b != b'0')
+      && parent_id.bytes().any(|b| b != b'0');
+    if !valid {
+      return None;
+    }
+    Some(Self {
+      trace_id: trace_id.to_string(),
+      parent_id: parent_id.to_string(),
+      span_id: format!("{:016x}", random().max(1)),
+      flags: flags.to_string(),
+      state: crate::header(request, TRACESTATE_HEADER)
+        .map(str::trim)
+        .filter(|s| !s.is_empty())
+        .map(str::to_string),
+    })
+  }
+
+  /// The `traceparent` of the request span, for the command.
+  fn traceparent(&self) -> String {
+    format!("00-{}-{}-{}", self.trace_id, self.span_id, self.flags)
+  }
+}
+
+/// Whether `value` is `length` lowercase hexadecimal digits.
+fn is_hex(value: &str, length: usize) -> bool {
+  value.len() == length
+    && value
+      .bytes()
+      .all(|b| matches!(b, b'0'..=b'9' | b'a'..=b'f'))
+}
+
+thread_local! {
+  static CURRENT: RefCell<Option<Arc<RequestTrace>>> = const { RefCell::new(None) };
+}
+
+/// The trace of a request, completed once dropped.
+pub(crate) struct RequestTrace {
+  id: String,
+  context: Option<TraceContext>,
+  #[cfg(feature = "tracing")]
+  span: tracing::Span,
+  started: Instant,
+  /// Only kept with a logger, metrics or an inspector.
+  record: Option<Mutex<RequestRecord>>,
+  /// The arguments of the invoke, only kept for an inspector recording them.
+  args: Mutex<Option<JsonValue>>,
+  observers: Arc<Observers>,
+  /// Only kept with `Invoke::server_timing`.
+  timings: Option<Mutex<Timings>>,
+}
+
+#[derive(Default)]
+struct Timings {
+  /// The time from the accept of the request to the invoke being read.
+  read: Option<Duration>,
+  /// When the invoke was dispatched to its window.
+  dispatched: Option<Instant>,
+}
+
+/// Keeps a trace entered on the current thread.
+pub(crate) struct Entered<'a> {
+  #[cfg(feature = "tracing")]
+  _span: tracing::span::Entered<'a>,
+  previous: Option<Arc<RequestTrace>>,
+  _trace: PhantomData<&'a RequestTrace>,
+}
+
+impl Drop for Entered<'_> {
+  fn drop(&mut self) {
+    let previous = self.previous.take();
+    let _ = CURRENT.try_with(|current| *current.borrow_mut() = previous);
+  }
+}
+
+impl RequestTrace {
+  pub(crate) fn new(request: &Request, observers: &Arc<Observers>) -> Self {
+    let origin = crate::header(request, "Origin");
+    let id = crate::header(request, REQUEST_ID_HEADER)
+      .filter(|id| valid_request_id(id))
+      .map_or_else(generate_request_id, str::to_string);
+    let context = TraceContext::from_request(request);
+    if let Some(metrics) = &observers.metrics {
+      metrics.start();
+    }
+    let recorded =
+      !observers.loggers.is_empty() || observers.metrics.is_some() || observers.inspector.is_some();
+    let record = recorded.then(|| {
+      Mutex::new(RequestRecord {
+        request_id: id.clone(),
+        method: request.method().to_string(),
+        path: request.url().to_string(),
+        http_version: request.http_version().to_string(),
+        window: None,
+        cmd: None,
+        status: None,
+        bytes: None,
+        request_bytes: request.body_length().map(|length| length as u64),
+        duration: Duration::default(),
+        client: proxy::client(
+          request,
+          &observers.trusted_proxies,
+          observers.proxied.as_deref(),
+        ),
+        scheme: proxy::scheme(
+          request,
+          &observers.trusted_proxies,
+          observers.proxied.as_deref(),
+        ),
+        origin: origin.map(str::to_string),
+        trace_id: context.as_ref().map(|c| c.trace_id.clone()),
+        span_id: context.as_ref().map(|c| c.span_id.clone()),
+        parent_span_id: context.as_ref().map(|c| c.parent_id.clone()),
+      })
+    });
+    Self {
+      #[cfg(feature = "tracing")]
+      span: tracing::info_span!(
+        "request",
+        request_id = %id,
+        method = %request.method(),
+        path = request.url(),
+        origin,
+        trace_id = context.as_ref().map(|c| c.trace_id.as_str()),
+        span_id = context.as_ref().map(|c| c.span_id.as_str()),
+        parent_span_id = context.as_ref().map(|c| c.parent_id.as_str()),
+        window = tracing::field::Empty,
+        cmd = tracing::field::Empty,
+        status = tracing::field::Empty,
+        duration_ms = tracing::field::Empty,
+      ),
+      id,
+      context,
+      started: Instant::now(),
+      record,
+      args: Default::default(),
+      observers: observers.clone(),
+      timings: observers.server_timing.then(Default::default),
+    }
+  }
+
+  /// The id of the request.
+  pub(crate) fn id(&self) -> &str {
+    &self.id
+  }
+
+  /// Enters the trace on the current thread until the guard is dropped.
+  pub(crate) fn enter(self: &Arc<Self>) -> Entered<'_> {
+    let previous = CURRENT.with(|current| current.borrow_mut().replace(self.clone()));
+    Entered {
+      #[cfg(feature = "tracing")]
+      _span: self.span.enter(),
+      previous,
+      _trace: PhantomData,
+    }
+  }
+
+  /// Records the invoke read from the request.
+  pub(crate) fn record_invoke(&self, window: &str, cmd: &str, args: &JsonValue) {
+    if let Some(inspector) = &self.observers.inspector {
+      if let Some(args) = inspector.redact(args) {
+        self.args.lock().unwrap().replace(args);
+      }
+    }
+    if let Some(timings) = &self.timings {
+      timings.lock().unwrap().read.replace(self.started.elapsed());
+    }
+    #[cfg(feature = "tracing")]
+    {
+      self.span.record("window", window);
+      self.span.record("cmd", cmd);
+      tracing::debug!(parent: &self.span, "dispatching the invoke");
+    }
+    if let Some(record) = &self.record {
+      let mut record = record.lock().unwrap();
+      record.window.replace(window.to_string());
+      record.cmd.replace(cmd.to_string());
+    }
+  }
+
+  /// The `Server-Timing` header value of the response, in milliseconds.
+  fn server_timing(&self) -> Option<String> {
+    let timings = self.timings.as_ref()?.lock().unwrap();
+    let ms = |duration: Duration| duration.as_secs_f64() * 1000.0;
+    let mut metrics = Vec::new();
+    if let Some(read) = timings.read {
+      metrics.push(format!("read;dur={:.2}", ms(read)));
+      if let Some(dispatched) = timings.dispatched {
+        let queue = dispatched.duration_since(self.started).saturating_sub(read);
+        metrics.push(format!("queue;dur={:.2}", ms(queue)));
+        metrics.push(format!("cmd;dur={:.2}", ms(dispatched.elapsed())));
+      }
+    }
+    metrics.push(format!("total;dur={:.2}", ms(self.started.elapsed())));
+    Some(metrics.join(", "))
+  }
+
+  fn record_response(&self, status: u16, bytes: Option<u64>) {
+    #[cfg(feature = "tracing")]
+    self.span.record("status", status);
+    if let Some(record) = &self.record {
+      let mut record = record.lock().unwrap();
+      record.status.replace(status);
+      record.bytes = bytes;
+    }
+  }
+}
+
+impl Drop for RequestTrace {
+  fn drop(&mut self) {
+    let duration = self.started.elapsed();
+    #[cfg(feature = "tracing")]
+    {
+      let duration_ms = duration.as_millis() as u64;
+      self.span.record("duration_ms", duration_ms);
+      tracing::info!(parent: &self.span, duration_ms, "request completed");
+    }
+    if let Some(record) = self.record.take() {
+      let mut record = record.into_inner().unwrap();
+      record.duration = duration;
+      if let Some(metrics) = &self.observers.metrics {
+        metrics.finish(&record);
+      }
+      if let Some(inspector) = &self.observers.inspector {
+        inspector.record(&record, self.args.lock().unwrap().take());
+      }
+      for logger in &self.observers.loggers {
+        logger(&record);
+      }
+    }
+  }
+}
+
+/// Records the status and body size of the response sent in the trace of the current thread.
+pub(crate) fn record_response(status: u16, bytes: Option<u64>) {
+  let _ = CURRENT.try_with(|current| {
+    if let Some(trace) = &*current.borrow() {
+      trace.record_response(status, bytes);
+    }
+  });
+}
+
+/// The trace entered on the current thread, if any.
+pub(crate) fn current() -> Option<Arc<RequestTrace>> {
+  CURRENT
+    .try_with(|current| current.borrow().clone())
+    .ok()
+    .flatten()
+}
+
+/// Records that the invoke of the trace of the current thread is being dispatched to its window,
+/// once for batches.
+pub(crate) fn record_dispatch() {
+  let _ = CURRENT.try_with(|current| {
+    if let Some(timings) = current.borrow().as_ref().and_then(|t| t.timings.as_ref()) {
+      timings
+        .lock()
+        .unwrap()
+        .dispatched
+        .get_or_insert_with(Instant::now);
+    }
+  });
+}
+
+/// The headers added to the responses of the trace of the current thread: its `X-Request-Id` and
+/// its `Server-Timing` readable by `allowed_origin`, the origin allowed by CORS if any.
+pub(crate) fn response_headers(allowed_origin: Option<&str>) -> Vec<Header> {
+  let trace = match current() {
+    Some(trace) => trace,
+    None => return Vec::new(),
+  };
+  let mut headers = vec![
+    Header::from_bytes(REQUEST_ID_HEADER, trace.id.as_str()).unwrap(),
+    Header::from_bytes("Access-Control-Expose-Headers", REQUEST_ID_HEADER).unwrap(),
+  ];
+  if let Some(timing) = trace.server_timing() {
+    headers.push(Header::from_bytes("Server-Timing", timing).unwrap());
+    if let Some(origin) = allowed_origin {
+      headers.push(Header::from_bytes("Timing-Allow-Origin", origin).unwrap());
+    }
+  }
+  headers
+}
+
+/// The value of a request header set by the trace of the current thread, which is `None` for the
+/// headers it doesn't set and `Some(None)` for the `traceparent` of requests without a context.
+pub(crate) fn traced_header(name: &str) -> Option<Option<String>> {
+  let is = |header: &str| name.eq_ignore_ascii_case(header);
+  if !(is(REQUEST_ID_HEADER) || is(TRACEPARENT_HEADER) || is(TRACESTATE_HEADER)) {
+    return None;
+  }
+  let value = CURRENT
+    .try_with(|current| {
+      let current = current.borrow();
+      let trace = current.as_ref()?;
+      if is(REQUEST_ID_HEADER) {
+        return Some(trace.id.clone());
+      }
+      let context = trace.context.as_ref()?;
+      if is(TRACEPARENT_HEADER) {
+        Some(context.traceparent())
+      } else {
+        context.state.clone()
+      }
+    })
+    .ok()
+    .flatten();
+  Some(value)
+}
+
+fn valid_request_id(id: &str) -> bool {
+  !id.is_empty() && id.len() <= MAX_REQUEST_ID_LENGTH && id.bytes().all(|b| b.is_ascii_graphic())
+}
+
+/// Generates a random 128-bit id, as 32 hexadecimal digits.
+fn generate_request_id() -> String {
+  format!("{:016x}{:016x}", random(), random())
+}
+
+pub(crate) fn random() -> u64 {
+  // the keys of a `RandomState` are random, and differ for each one
+  RandomState::new().build_hasher().finish()
+}
+
+#[cfg(test)]
+mod tests {
+  use tiny_http::TestRequest;
+
+  use super::*;
+
+  fn context(traceparent: &str, tracestate: Option<&str>) -> Option<TraceContext> {
+    let mut request =
+      TestRequest::new().with_header(Header::from_bytes(TRACEPARENT_HEADER, traceparent).unwrap());
+    if let Some(tracestate) = tracestate {
+      request = request.with_header(Header::from_bytes(TRACESTATE_HEADER, tracestate).unwrap());
+    }
+    TraceContext::from_request(&request.into())
+  }
+
+  #[test]
+  fn reads_the_trace_context() {
+    let context = context(
+      "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01",
+      Some("congo=t61rcWkgMzE"),
+    )
+    .unwrap();
+    assert_eq!(context.trace_id, "4bf92f3577b34da6a3ce929d0e0e4736");
+    assert_eq!(context.parent_id, "00f067aa0ba902b7");
+    assert_eq!(context.state.as_deref(), Some("congo=t61rcWkgMzE"));
+    // the request gets a span of its own
+    let traceparent = context.traceparent();
+    assert!(traceparent.starts_with("00-4bf92f3577b34da6a3ce929d0e0e4736-"));
+    assert!(traceparent.ends_with("-01"));
+    assert_ne!(
+      traceparent,
+      "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01"
+    );
+  }
+
+  #[test]
+  fn accepts_the_fields_of_later_versions() {
+    assert!(context(
+      "01-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01-extra",
+      None
+    )
+    .is_some());
+  }
+
+  #[test]
+  fn ignores_invalid_traceparents() {
+    for traceparent in [
+      "",
+      "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7",
+      "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01-extra",
+      "ff-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01",
+      "00-4BF92F3577B34DA6A3CE929D0E0E4736-00f067aa0ba902b7-01",
+      "00-00000000000000000000000000000000-00f067aa0ba902b7-01",
+      "00-4bf92f3577b34da6a3ce929d0e0e4736-0000000000000000-01",
+      "00-4bf92f3577b34da6-00f067aa0ba902b7-01",
+    ] {
+      assert!(context(traceparent, None).is_none(), "{}", traceparent);
+    }
+  }
+}
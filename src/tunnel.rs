@@ -0,0 +1,34 @@
+// Copyright 2019-2021 Tauri Programme within The Commons Conservancy
+// SPDX-License-Identifier: Apache-2.0
+// SPDX-License-Identifier: MIT
+
+//! The tunnel of `Invoke::tunnel`, exposing the server through a provider such as cloudflared,
+//! ngrok or Tailscale Funnel.
+
+use tauri::Url;
+
+/// A tunnel forwarding a public URL to the invoke server, started once the server listens.
+///
+/// ```rust,ignore
+/// struct Ngrok(Option<std::process::Child>);
+///
+/// impl tauri_invoke_http::Tunnel for Ngrok {
+///   fn start(&mut self, port: u16) -> std::io::Result<String> {
+///     let child = spawn_ngrok(port)?;
+///     let url = read_public_url(&child)?;
+///     // kept so the tunnel lives as long as the app
+///     self.0.replace(child);
+///     Ok(url)
+///   }
+/// }
+/// ```
+pub trait Tunnel: Send + 'static {
+  /// Starts forwarding to `localhost:{port}`, returning the public URL.
+  fn start(&mut self, port: u16) -> std::io::Result<String>;
+}
+
+/// The origin of a public URL, allowed to make requests.
+pub(crate) fn origin(public_url: &str) -> Option<String> {
+  let url = Url::parse(public_url).ok()?;
+  url.has_host().then(|| url.origin().ascii_serialization())
+}
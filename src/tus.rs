@@ -0,0 +1,414 @@
+// Copyright 2019-2021 Tauri Programme within The Commons Conservancy
+// SPDX-License-Identifier: Apache-2.0
+// SPDX-License-Identifier: MIT
+
+//! Resumable uploads following the [tus protocol](https://tus.io/protocols/resumable-upload).
+//!
+//! Uploads are created with `POST /__tus/{window}`, resumed with `HEAD` and `PATCH` requests on the
+//! returned `Location` and dispatch the configured command once the whole file has been received.
+
+use std::{
+  collections::HashMap,
+  fs::OpenOptions,
+  io::Read,
+  path::PathBuf,
+  str::FromStr,
+  sync::Mutex,
+  time::{Duration, Instant},
+};
+
+use base64::Engine;
+use percent_encoding::{utf8_percent_encode, AsciiSet, NON_ALPHANUMERIC};
+use serde_json::{Map, Value as JsonValue};
+use tauri::{AppHandle, Runtime, Url};
+use tiny_http::{Header, Method, Request, Response};
+
+use crate::{
+  body,
+  buffer::{self, PooledBody},
+  decode_segment,
+  dispatch::Dispatcher,
+  header,
+  inspector::token_matches,
+  invoke_window, query_args, random_token, window_reachable, Config, ErrorCode, ErrorFormat,
+  HttpError, IncomingInvoke,
+};
+
+/// The tus protocol version implemented by the server.
+pub(crate) const TUS_VERSION: &str = "1.0.0";
+
+/// The default maximum size of uploads.
+pub(crate) const DEFAULT_MAX_SIZE: u64 = 1024 * 1024 * 1024;
+
+/// The time after which the uploads receiving no bytes are abandoned and their file removed.
+const ABANDONED_TTL: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// The characters of the window label encoded in the upload `Location`.
+const SEGMENT: &AsciiSet = &NON_ALPHANUMERIC
+  .remove(b'-')
+  .remove(b'_')
+  .remove(b'.')
+  .remove(b'~');
+
+/// The response sent when an upload request doesn't dispatch the command.
+pub(crate) type TusResponse = Response<PooledBody>;
+
+struct Upload {
+  window: String,
+  path: PathBuf,
+  length: u64,
+  offset: u64,
+  /// Whether a `PATCH` request is writing to the upload.
+  busy: bool,
+  metadata: Map<String, JsonValue>,
+  invoke_key: String,
+  /// When the upload was created or last received bytes.
+  touched: Instant,
+}
+
+/// The uploads in progress.
+#[derive(Default)]
+pub(crate) struct TusUploads {
+  uploads: Mutex<HashMap<String, Upload>>,
+}
+
+impl TusUploads {
+  /// Removes the uploads that received no bytes for [`ABANDONED_TTL`].
+  fn remove_abandoned(&self) {
+    let now = Instant::now();
+    self.uploads.lock().unwrap().retain(|_, upload| {
+      let keep = upload.busy || now.duration_since(upload.touched) < ABANDONED_TTL;
+      if !keep {
+        let _ = std::fs::remove_file(&upload.path);
+      }
+      keep
+    });
+  }
+
+  fn remove(&self, id: &str) {
+    if let Some(upload) = self.uploads.lock().unwrap().remove(id) {
+      let _ = std::fs::remove_file(upload.path);
+    }
+  }
+}
+
+impl Drop for TusUploads {
+  fn drop(&mut self) {
+    for (_, upload) in self.uploads.get_mut().unwrap().drain() {
+      let _ = std::fs::remove_file(upload.path);
+    }
+  }
+}
+
+fn response(status: u16) -> TusResponse {
+  buffer::response(Vec::new())
+    .with_status_code(status)
+    .with_header(Header::from_bytes("Tus-Resumable", TUS_VERSION).unwrap())
+    .with_header(expose_headers())
+}
+
+fn expose_headers() -> Header {
+  Header::from_str(
+    "Access-Control-Expose-Headers: Location, Upload-Offset, Upload-Length, Tus-Resumable",
+  )
+  .unwrap()
+}
+
+fn error_response(status: u16, code: ErrorCode, message: &str, format: ErrorFormat) -> TusResponse {
+  tus_error(&HttpError::new(status, code, message), format)
+}
+
+fn tus_error(error: &HttpError, format: ErrorFormat) -> TusResponse {
+  crate::error::transport_error(error, format)
+    .with_header(Header::from_bytes("Tus-Resumable", TUS_VERSION).unwrap())
+    .with_header(expose_headers())
+}
+
+/// The headers of the `OPTIONS` response, describing the server capabilities.
+pub(crate) fn options_headers() -> Vec<Header> {
+  vec![
+    Header::from_bytes("Tus-Resumable", TUS_VERSION).unwrap(),
+    Header::from_bytes("Tus-Version", TUS_VERSION).unwrap(),
+    Header::from_str("Tus-Extension: creation,termination").unwrap(),
+  ]
+}
+
+/// Reads the `Upload-Metadata` header: comma separated `key base64(value)` pairs.
+fn parse_metadata(metadata: &str) -> Result<Map<String, JsonValue>, ()> {
+  let mut map = Map::new();
+  for pair in metadata.split(',').map(str::trim).filter(|p| !p.is_empty()) {
+    let mut pieces = pair.splitn(2, ' ');
+    let key = pieces.next().unwrap_or_default();
+    let value = match pieces.next() {
+      Some(value) => {
+        let value = base64::engine::general_purpose::STANDARD
+          .decode(value.trim())
+          .map_err(|_| ())?;
+        String::from_utf8(value).map_err(|_| ())?
+      }
+      None => String::new(),
+    };
+    map.insert(key.into(), value.into());
+  }
+  Ok(map)
+}
+
+/// Handles a request to the `/__tus/` routes.
+///
+/// Returns the invoke to dispatch once the upload is complete, or the response to send otherwise.
+pub(crate) fn handle<R: Runtime>(
+  app: &AppHandle<R>,
+  request: &mut Request,
+  url: &Url,
+  config: &Config,
+  uploads: &TusUploads,
+  dispatcher: &Dispatcher,
+) -> Result<IncomingInvoke<R>, TusResponse> {
+  let error =
+    |status, code, message: &str| error_response(status, code, message, config.error_format);
+  let cmd = config
+    .tus_command
+    .as_ref()
+    .ok_or_else(|| error(404, ErrorCode::UploadsDisabled, "tus uploads are disabled"))?;
+  let mut pieces = url.path().split('/').skip(2);
+  let window_label = decode_segment(pieces.next().unwrap_or_default())
+    .map_err(|e| error(400, ErrorCode::InvalidUrl, &e.message))?;
+  let upload_id = pieces.next();
+  let (_, invoke_key) = query_args(url).map_err(|e| error(400, ErrorCode::InvalidArgs, &e))?;
+  // an upload is only reachable on the route of its window, with the key it was created with
+  let authorize = |upload: &Upload| {
+    if upload.window != window_label || !window_reachable(&upload.window, config) {
+      return Err(error(404, ErrorCode::UnknownUpload, "upload not found"));
+    }
+    match invoke_key.as_deref() {
+      Some(sent) if token_matches(sent, &upload.invoke_key) => Ok(()),
+      Some(_) => Err(error(
+        403,
+        ErrorCode::InvalidInvokeKey,
+        "the `__TAURI_INVOKE_KEY__` of the request is not the one of the upload",
+      )),
+      None => Err(error(
+        401,
+        ErrorCode::MissingInvokeKey,
+        "the request has no `__TAURI_INVOKE_KEY__`",
+      )),
+    }
+  };
+
+  match (request.method(), upload_id) {
+    (Method::Post, None) => {
+      let window = invoke_window(app, &window_label, config)
+        .ok_or_else(|| error(404, ErrorCode::UnknownWindow, "window not found"))?;
+      let length = header(request, "Upload-Length")
+        .and_then(|l| l.parse::<u64>().ok())
+        .ok_or_else(|| {
+          error(
+            400,
+            ErrorCode::InvalidUploadHeader,
+            "missing or invalid Upload-Length header",
+          )
+        })?;
+      if let Some(max_size) = config.tus_max_size {
+        if length > max_size {
+          return Err(error(
+            413,
+            ErrorCode::BodyTooLarge,
+            "upload exceeds the maximum size",
+          ));
+        }
+      }
+      let metadata = parse_metadata(header(request, "Upload-Metadata").unwrap_or_default())
+        .map_err(|_| {
+          error(
+            400,
+            ErrorCode::InvalidUploadHeader,
+            "invalid Upload-Metadata header",
+          )
+        })?;
+      // checked like an invoke, so only the clients of the window can create uploads
+      dispatcher
+        .authorize(&window, invoke_key.as_deref())
+        .map_err(|e| error(e.status, e.code, &e.message))?;
+      let invoke_key = invoke_key.unwrap_or_default();
+      uploads.remove_abandoned();
+
+      let id = random_token();
+      let (path, _) = body::create_temp_file("tus")
+        .map_err(|e| error(500, ErrorCode::InternalError, &e.to_string()))?;
+      uploads.uploads.lock().unwrap().insert(
+        id.clone(),
+        Upload {
+          window: window.label().into(),
+          path,
+          length,
+          offset: 0,
+          busy: false,
+          metadata,
+          invoke_key,
+          touched: Instant::now(),
+        },
+      );
+
+      let location = format!(
+        "/__tus/{}/{}",
+        utf8_percent_encode(&window_label, SEGMENT),
+        id
+      );
+      Err(
+        response(201)
+          .with_header(Header::from_bytes("Location", location).unwrap())
+          .with_header(Header::from_str("Upload-Offset: 0").unwrap()),
+      )
+    }
+    (Method::Head, Some(id)) => {
+      let uploads = uploads.uploads.lock().unwrap();
+      let upload = uploads
+        .get(id)
+        .ok_or_else(|| error(404, ErrorCode::UnknownUpload, "upload not found"))?;
+      authorize(upload)?;
+      Err(
+        response(200)
+          .with_header(Header::from_bytes("Upload-Offset", upload.offset.to_string()).unwrap())
+          .with_header(Header::from_bytes("Upload-Length", upload.length.to_string()).unwrap())
+          .with_header(Header::from_str("Cache-Control: no-store").unwrap()),
+      )
+    }
+    (Method::Patch, Some(id)) => {
+      if header(request, "Content-Type") != Some("application/offset+octet-stream") {
+        return Err(error(
+          415,
+          ErrorCode::UnsupportedMediaType,
+          "expected application/offset+octet-stream",
+        ));
+      }
+      let offset = header(request, "Upload-Offset")
+        .and_then(|o| o.parse::<u64>().ok())
+        .ok_or_else(|| {
+          error(
+            400,
+            ErrorCode::InvalidUploadHeader,
+            "missing or invalid Upload-Offset header",
+          )
+        })?;
+      let (path, length) = {
+        let mut uploads = uploads.uploads.lock().unwrap();
+        let upload = uploads
+          .get_mut(id)
+          .ok_or_else(|| error(404, ErrorCode::UnknownUpload, "upload not found"))?;
+        authorize(upload)?;
+        if upload.busy {
+          return Err(error(
+            409,
+            ErrorCode::UploadInProgress,
+            "another request is writing to the upload",
+          ));
+        }
+        if upload.offset != offset {
+          return Err(error(
+            409,
+            ErrorCode::UploadOffsetMismatch,
+            "Upload-Offset does not match the upload offset",
+          ));
+        }
+        // the upload is written by this request only, until its offset is updated
+        upload.busy = true;
+        (upload.path.clone(), upload.length)
+      };
+
+      // a failed transfer keeps the bytes received so far, which the client resumes from
+      let transfer = OpenOptions::new().append(true).open(&path).map(|mut file| {
+        let remaining = length.saturating_sub(offset);
+        let written = std::io::copy(&mut request.as_reader().take(remaining), &mut file);
+        let received = file.metadata().map(|m| m.len()).unwrap_or(offset);
+        (received, written)
+      });
+
+      let mut uploads_lock = uploads.uploads.lock().unwrap();
+      let upload = match uploads_lock.get_mut(id) {
+        Some(upload) => upload,
+        None => return Err(error(404, ErrorCode::UnknownUpload, "upload not found")),
+      };
+      upload.busy = false;
+      let (received, written) =
+        transfer.map_err(|e| error(500, ErrorCode::InternalError, &e.to_string()))?;
+      upload.offset = received;
+      upload.touched = Instant::now();
+      if let Err(e) = written {
+        return Err(error(500, ErrorCode::InternalError, &e.to_string()));
+      }
+      let offset_header = Header::from_bytes("Upload-Offset", upload.offset.to_string()).unwrap();
+      if upload.offset < upload.length {
+        return Err(response(204).with_header(offset_header));
+      }
+
+      // the upload is complete: hand the file over to the command
+      let upload = uploads_lock.remove(id).unwrap();
+      drop(uploads_lock);
+      let window = invoke_window(app, &upload.window, config)
+        .ok_or_else(|| error(404, ErrorCode::UnknownWindow, "window not found"))?;
+      let mut args = Map::new();
+      args.insert(
+        "path".into(),
+        upload.path.to_string_lossy().into_owned().into(),
+      );
+      args.insert("metadata".into(), upload.metadata.into());
+      args.insert("cmd".into(), cmd.clone().into());
+      args.insert("callback".into(), 0.into());
+      args.insert("error".into(), 0.into());
+      args.insert("__TAURI_INVOKE_KEY__".into(), upload.invoke_key.into());
+      let payload = serde_json::from_value(JsonValue::Object(args))
+        .map_err(|e| error(500, ErrorCode::InternalError, &e.to_string()))?;
+      Ok(IncomingInvoke {
+        window,
+        payload,
+        spool: None,
+        headers: vec![
+          offset_header,
+          Header::from_bytes("Tus-Resumable", TUS_VERSION).unwrap(),
+          expose_headers(),
+        ],
+      })
+    }
+    (Method::Delete, Some(id)) => {
+      match uploads.uploads.lock().unwrap().get(id) {
+        Some(upload) => authorize(upload)?,
+        None => return Err(error(404, ErrorCode::UnknownUpload, "upload not found")),
+      }
+      uploads.remove(id);
+      Err(response(204))
+    }
+    _ => Err(tus_error(
+      &HttpError::method_not_allowed("POST, HEAD, PATCH, DELETE, OPTIONS"),
+      config.error_format,
+    )),
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use serde_json::json;
+
+  use super::*;
+
+  #[test]
+  fn parses_metadata() {
+    let metadata =
+      parse_metadata("filename d29ybGRfZG9taW5hdGlvbl9wbGFuLnBkZg==,is_confidential").unwrap();
+    assert_eq!(
+      JsonValue::Object(metadata),
+      json!({ "filename": "world_domination_plan.pdf", "is_confidential": "" })
+    );
+    assert!(parse_metadata("").unwrap().is_empty());
+    assert_eq!(
+      parse_metadata(" name  Y2Fmw6k= , ").unwrap()["name"],
+      "café"
+    );
+  }
+
+  #[test]
+  fn rejects_invalid_metadata() {
+    assert!(parse_metadata("filename not-base64!").is_err());
+    // not UTF-8
+    assert!(parse_metadata("filename //79").is_err());
+  }
+}
@@ -0,0 +1,508 @@
+// Copyright 2019-2021 Tauri Programme within The Commons Conservancy
+// SPDX-License-Identifier: Apache-2.0
+// SPDX-License-Identifier: MIT
+
+//! A [WebSocket](https://www.rfc-editor.org/rfc/rfc6455) bridge carrying the event subscriptions
+//! and emissions of a client, served on the `Invoke::websocket_port`.
+//!
+//! The client sends `listen`, `unlisten` and `emit` messages and receives `event` messages,
+//! mirroring `@tauri-apps/api/event`. The handshake request sends the `__TAURI_INVOKE_KEY__`
+//! query parameter, checked like the key of an invoke. Upgraded `tiny_http` connections can't be read and written
+//! from different threads, so the bridge has its own listener.
+
+use std::{
+  collections::HashSet,
+  io::{BufRead, BufReader, ErrorKind, Read, Write},
+  net::{TcpListener, TcpStream},
+  sync::{Arc, Mutex},
+  time::Duration,
+};
+
+use base64::Engine;
+use serde::Deserialize;
+use serde_json::{json, Value as JsonValue};
+use tauri::{AppHandle, Manager, Runtime, Url};
+
+use crate::{
+  dispatch::Dispatcher,
+  events::{
+    authorize, can_subscribe, emit_event, not_allowed, sink_backpressure, valid_event_name,
+    EventHub, Scope, Sink,
+  },
+  origin_allowed,
+  stream::BufferedWriter,
+  trace::log_error,
+  valid_origin, Config, ErrorCode, HttpError,
+};
+
+const GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+/// The maximum size of the handshake request head.
+const MAX_HEAD_SIZE: usize = 8 * 1024;
+/// The maximum size of a client message.
+const MAX_MESSAGE_SIZE: u64 = 1024 * 1024;
+/// The time a write to a client may block, after which the client is dropped.
+const WRITE_TIMEOUT: Duration = Duration::from_secs(10);
+/// The time a connection stays idle before it is pinged, and is then closed without an answer.
+const IDLE_TIMEOUT: Duration = Duration::from_secs(30);
+
+const CONTINUATION: u8 = 0x0;
+const TEXT: u8 = 0x1;
+const CLOSE: u8 = 0x8;
+const PING: u8 = 0x9;
+const PONG: u8 = 0xa;
+
+#[derive(Deserialize)]
+#[serde(tag = "type", rename_all = "camelCase")]
+enum ClientMessage {
+  Listen {
+    event: String,
+    #[serde(rename = "windowLabel")]
+    window_label: Option<String>,
+  },
+  Unlisten {
+    event: String,
+    #[serde(rename = "windowLabel")]
+    window_label: Option<String>,
+  },
+  Emit {
+    event: String,
+    #[serde(rename = "windowLabel")]
+    window_label: Option<String>,
+    #[serde(default)]
+    payload: JsonValue,
+  },
+}
+
+/// Accepts the WebSocket connections on a background thread.
+pub(crate) fn start<R: Runtime>(
+  app: AppHandle<R>,
+  port: u16,
+  config: Arc<Config>,
+  hub: Arc<EventHub>,
+  dispatcher: Arc<Dispatcher>,
+) {
+  let listener = match TcpListener::bind(("localhost", port)) {
+    Ok(listener) => listener,
+    Err(e) => {
+      log_error!(
+        "failed to start the WebSocket bridge on port {}: {}",
+        port,
+        e
+      );
+      return;
+    }
+  };
+  std::thread::spawn(move || {
+    for stream in listener.incoming().flatten() {
+      let app = app.clone();
+      let config = config.clone();
+      let hub = hub.clone();
+      let dispatcher = dispatcher.clone();
+      std::thread::spawn(move || {
+        let _ = serve(&app, stream, &config, &hub, &dispatcher);
+      });
+    }
+  });
+}
+
+/// Runs a connection until the client closes it.
+fn serve<R: Runtime>(
+  app: &AppHandle<R>,
+  stream: TcpStream,
+  config: &Config,
+  hub: &Arc<EventHub>,
+  dispatcher: &Dispatcher,
+) -> std::io::Result<()> {
+  stream.set_write_timeout(Some(WRITE_TIMEOUT))?;
+  // also bounds the handshake
+  stream.set_read_timeout(Some(IDLE_TIMEOUT))?;
+  let mut reader = BufReader::new(stream.try_clone()?);
+  let mut writer = stream;
+  let handshake = handshake(&mut reader, config).and_then(|(key, origin, invoke_key)| {
+    match authorize(app, invoke_key.as_deref(), None, dispatcher) {
+      Ok(()) => Ok((key, origin)),
+      Err(e) if e.status == 401 => Err((401, "Unauthorized")),
+      Err(e) if e.status == 403 => Err((403, "Forbidden")),
+      Err(_) => Err((503, "Service Unavailable")),
+    }
+  });
+  let (key, origin) = match handshake {
+    Ok(handshake) => handshake,
+    Err((status, reason)) => {
+      return write!(
+        writer,
+        "HTTP/1.1 {} {}\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+        status, reason
+      );
+    }
+  };
+  write!(
+    writer,
+    "HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Accept: {}\r\n\r\n",
+    accept_key(&key)
+  )?;
+
+  let shutdown = writer.try_clone()?;
+  let writer = Arc::new(Mutex::new(writer));
+  let frames = writer.clone();
+  let messages = BufferedWriter::spawn_with(
+    move |message| write_frame(&mut *frames.lock().unwrap(), TEXT, message),
+    config.stream_buffer,
+    sink_backpressure(config),
+    None,
+    None,
+  );
+  let id = hub.add_subscriber(Sink::WebSocket(messages, shutdown), HashSet::new());
+  let mut pinged = false;
+  let result = (|| loop {
+    match reader.fill_buf().map(|buffered| buffered.is_empty()) {
+      Ok(true) => return Ok(()),
+      Ok(false) => pinged = false,
+      Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) && !pinged => {
+        // browsers answer pings, so only gone clients stay silent
+        write_frame(&mut *writer.lock().unwrap(), PING, &[])?;
+        pinged = true;
+        continue;
+      }
+      Err(e) => return Err(e),
+    }
+    let (opcode, data) = read_message(&mut reader)?;
+    match opcode {
+      TEXT => {
+        if let Err(e) = handle_message(app, id, origin.as_deref(), &data, config, hub) {
+          let error = json!({ "type": "error", "code": e.code.as_str(), "message": e.message });
+          send_text(&writer.lock().unwrap(), &error.to_string())?;
+        }
+      }
+      PING => write_frame(&mut *writer.lock().unwrap(), PONG, &data)?,
+      CLOSE => {
+        write_frame(&mut *writer.lock().unwrap(), CLOSE, &data)?;
+        return Ok(());
+      }
+      _ => {}
+    }
+  })();
+  hub.remove_subscriber(id);
+  result
+}
+
+/// The head of a handshake request: its `Sec-WebSocket-Key`, `Origin`
+/// and `__TAURI_INVOKE_KEY__` query parameter.
+type Handshake = (String, Option<String>, Option<String>);
+
+/// Reads the handshake request, returning its head or the status and reason of the rejection.
+fn handshake(reader: &mut impl BufRead, config: &Config) -> Result<Handshake, (u16, &'static str)> {
+  let mut size = 0;
+  let mut line = String::new();
+  let mut upgrade = false;
+  let mut key = None;
+  let mut origin = None;
+  let mut invoke_key = None;
+  let mut request_line = true;
+  loop {
+    line.clear();
+    size += reader
+      .read_line(&mut line)
+      .map_err(|_| (400, "Bad Request"))?;
+    if size > MAX_HEAD_SIZE {
+      return Err((431, "Request Header Fields Too Large"));
+    }
+    let line = line.trim_end();
+    if line.is_empty() {
+      break;
+    }
+    if std::mem::take(&mut request_line) {
+      let target = line.split(' ').nth(1).unwrap_or_default();
+      invoke_key = Url::parse("ws://localhost")
+        .and_then(|base| base.join(target))
+        .ok()
+        .and_then(|url| {
+          url
+            .query_pairs()
+            .find(|(name, _)| name == "__TAURI_INVOKE_KEY__")
+            .map(|(_, value)| value.into_owned())
+        });
+      continue;
+    }
+    if let Some((name, value)) = line.split_once(':') {
+      let value = value.trim();
+      if name.eq_ignore_ascii_case("Upgrade") {
+        upgrade = value.eq_ignore_ascii_case("websocket");
+      } else if name.eq_ignore_ascii_case("Sec-WebSocket-Key") {
+        key = Some(value.to_string());
+      } else if name.eq_ignore_ascii_case("Origin") {
+        origin = Some(value.to_string());
+      }
+    }
+  }
+  if origin.as_deref().map_or(false, |o| !valid_origin(o)) {
+    return Err((400, "Bad Request"));
+  }
+  if !origin_allowed(origin.as_deref(), config) {
+    return Err((403, "Forbidden"));
+  }
+  match key {
+    Some(key) if upgrade => Ok((key, origin, invoke_key)),
+    _ => Err((400, "Bad Request")),
+  }
+}
+
+fn handle_message<R: Runtime>(
+  app: &AppHandle<R>,
+  id: usize,
+  origin: Option<&str>,
+  data: &[u8],
+  config: &Config,
+  hub: &Arc<EventHub>,
+) -> Result<(), HttpError> {
+  let message = serde_json::from_slice(data)
+    .map_err(|e| HttpError::new(400, ErrorCode::InvalidBody, e.to_string()))?;
+  match message {
+    ClientMessage::Listen {
+      event,
+      window_label,
+    } => {
+      let scope = scope(app, event, window_label)?;
+      if !can_subscribe(config, origin, &scope.1) {
+        return Err(not_allowed(&scope.1));
+      }
+      hub.listen(app, &scope, config);
+      hub.update_scopes(id, |scopes| {
+        scopes.insert(scope);
+      });
+    }
+    ClientMessage::Unlisten {
+      event,
+      window_label,
+    } => {
+      let scope = (window_label, event);
+      hub.update_scopes(id, |scopes| {
+        scopes.remove(&scope);
+      });
+    }
+    ClientMessage::Emit {
+      event,
+      window_label,
+      payload,
+    } => {
+      scope(app, event.clone(), window_label.clone())?;
+      emit_event(
+        app,
+        &event,
+        window_label.as_deref(),
+        origin,
+        payload,
+        config,
+      )?;
+    }
+  }
+  Ok(())
+}
+
+/// Checks the event name and window of a message.
+fn scope<R: Runtime>(
+  app: &AppHandle<R>,
+  event: String,
+  window: Option<String>,
+) -> Result<Scope, HttpError> {
+  if !valid_event_name(&event) {
+    return Err(HttpError::new(
+      400,
+      ErrorCode::InvalidArgs,
+      format!("invalid event name `{}`", event),
+    ));
+  }
+  if let Some(label) = &window {
+    if app.get_window(label).is_none() {
+      return Err(HttpError::new(
+        404,
+        ErrorCode::UnknownWindow,
+        format!("window `{}` not found", label),
+      ));
+    }
+  }
+  Ok((window, event))
+}
+
+/// Reads a message, joining its fragments, or a control frame.
+fn read_message(reader: &mut impl Read) -> std::io::Result<(u8, Vec<u8>)> {
+  let invalid = |message: &str| std::io::Error::new(std::io::ErrorKind::InvalidData, message);
+  let mut message_opcode = None;
+  let mut message = Vec::new();
+  loop {
+    let mut head = [0; 2];
+    reader.read_exact(&mut head)?;
+    let fin = head[0] & 0x80 != 0;
+    let opcode = head[0] & 0x0f;
+    if head[1] & 0x80 == 0 {
+      return Err(invalid("client frames must be masked"));
+    }
+    let len = match head[1] & 0x7f {
+      126 => {
+        let mut len = [0; 2];
+        reader.read_exact(&mut len)?;
+        u16::from_be_bytes(len) as u64
+      }
+      127 => {
+        let mut len = [0; 8];
+        reader.read_exact(&mut len)?;
+        u64::from_be_bytes(len)
+      }
+      len => len as u64,
+    };
+    if message.len() as u64 + len > MAX_MESSAGE_SIZE {
+      return Err(invalid("message too large"));
+    }
+    let mut mask = [0; 4];
+    reader.read_exact(&mut mask)?;
+    let mut data = vec![0; len as usize];
+    reader.read_exact(&mut data)?;
+    for (i, b) in data.iter_mut().enumerate() {
+      *b ^= mask[i % 4];
+    }
+
+    if opcode >= CLOSE {
+      return Ok((opcode, data));
+    }
+    if opcode != CONTINUATION {
+      message_opcode = Some(opcode);
+    }
+    message.extend(data);
+    if fin {
+      let opcode = message_opcode.ok_or_else(|| invalid("unexpected continuation frame"))?;
+      return Ok((opcode, message));
+    }
+  }
+}
+
+fn write_frame(writer: &mut impl Write, opcode: u8, data: &[u8]) -> std::io::Result<()> {
+  let mut frame = vec![0x80 | opcode];
+  match data.len() {
+    len if len < 126 => frame.push(len as u8),
+    len if len <= u16::MAX as usize => {
+      frame.push(126);
+      frame.extend_from_slice(&(len as u16).to_be_bytes());
+    }
+    len => {
+      frame.push(127);
+      frame.extend_from_slice(&(len as u64).to_be_bytes());
+    }
+  }
+  frame.extend_from_slice(data);
+  writer.write_all(&frame)?;
+  writer.flush()
+}
+
+/// Sends a text message.
+fn send_text(mut stream: &TcpStream, text: &str) -> std::io::Result<()> {
+  write_frame(&mut stream, TEXT, text.as_bytes())
+}
+
+fn accept_key(key: &str) -> String {
+  base64::engine::general_purpose::STANDARD.encode(sha1(format!("{}{}", key, GUID).as_bytes()))
+}
+
+/// SHA-1, only used to compute the handshake `Sec-WebSocket-Accept`.
+fn sha1(data: &[u8]) -> [u8; 20] {
+  let mut h: [u32; 5] = [0x67452301, 0xEFCDAB89, 0x98BADCFE, 0x10325476, 0xC3D2E1F0];
+  let mut message = data.to_vec();
+  message.push(0x80);
+  while message.len() % 64 != 56 {
+    message.push(0);
+  }
+  message.extend_from_slice(&(data.len() as u64 * 8).to_be_bytes());
+
+  for chunk in message.chunks(64) {
+    let mut w = [0u32; 80];
+    for (i, word) in chunk.chunks(4).enumerate() {
+      w[i] = u32::from_be_bytes([word[0], word[1], word[2], word[3]]);
+    }
+    for i in 16..80 {
+      w[i] = (w[i - 3] ^ w[i - 8] ^ w[i - 14] ^ w[i - 16]).rotate_left(1);
+    }
+    let [mut a, mut b, mut c, mut d, mut e] = h;
+    for (i, word) in w.iter().enumerate() {
+      let (f, k) = match i {
+        0..=19 => ((b & c) | (!b & d), 0x5A827999),
+        20..=39 => (b ^ c ^ d, 0x6ED9EBA1),
+        40..=59 => ((b & c) | (b & d) | (c & d), 0x8F1BBCDC),
+        _ => (b ^ c ^ d, 0xCA62C1D6),
+      };
+      let t = a
+        .rotate_left(5)
+        .wrapping_add(f)
+        .wrapping_add(e)
+        .wrapping_add(k)
+        .wrapping_add(*word);
+      e = d;
+      d = c;
+      c = b.rotate_left(30);
+      b = a;
+      a = t;
+    }
+    for (h, v) in h.iter_mut().zip([a, b, c, d, e]) {
+      *h = h.wrapping_add(v);
+    }
+  }
+
+  let mut digest = [0; 20];
+  for (bytes, h) in digest.chunks_mut(4).zip(h) {
+    bytes.copy_from_slice(&h.to_be_bytes());
+  }
+  digest
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn hex(digest: [u8; 20]) -> String {
+    digest.iter().map(|b| format!("{:02x}", b)).collect()
+  }
+
+  #[test]
+  fn hashes_with_sha1() {
+    assert_eq!(hex(sha1(b"")), "da39a3ee5e6b4b0d3255bfef95601890afd80709");
+    assert_eq!(
+      hex(sha1(b"abc")),
+      "a9993e364706816aba3e25717850c26c9cd0d89d"
+    );
+    // padded to two blocks
+    assert_eq!(
+      hex(sha1(
+        b"abcdbcdecdefdefgefghfghighijhijkijkljklmklmnlmnomnopnopq"
+      )),
+      "84983e441c3bd26ebaae4aa1f95129e5e54670f1"
+    );
+    assert_eq!(
+      hex(sha1(&[b'a'; 64])),
+      "0098ba824b5c16427bd7a1122a5a442a25ec644d"
+    );
+  }
+
+  #[test]
+  fn computes_the_accept_key() {
+    // the example of RFC 6455
+    assert_eq!(
+      accept_key("dGhlIHNhbXBsZSBub25jZQ=="),
+      "s3pPLMBiTxaQ9kYGzzhZRbK+xOo="
+    );
+  }
+
+  #[test]
+  fn reads_masked_messages() {
+    let mut frame = vec![0x81, 0x85, 1, 2, 3, 4];
+    frame.extend(
+      b"hello"
+        .iter()
+        .zip([1, 2, 3, 4].iter().cycle())
+        .map(|(b, m)| b ^ m),
+    );
+    assert_eq!(
+      read_message(&mut &frame[..]).unwrap(),
+      (TEXT, b"hello".to_vec())
+    );
+    // unmasked client frames are refused
+    assert!(read_message(&mut &[0x81, 0x00][..]).is_err());
+  }
+}